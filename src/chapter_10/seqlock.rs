@@ -0,0 +1,211 @@
+use std::cell::UnsafeCell;
+use std::mem::{size_of, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{fence, AtomicU8, AtomicUsize};
+
+/// A sequence lock for small `Copy` values that are written rarely
+/// and read often. Readers never block the writer: they copy the data
+/// optimistically and retry if a write happened in the meantime.
+///
+/// The data is only ever accessed through relaxed atomic byte operations,
+/// so a reader racing with a writer sees a torn copy (which it throws away)
+/// instead of causing a data race. Because of that, `T` must implement
+/// `NoUninit`: padding bytes would be read as uninitialized memory.
+#[allow(dead_code)]
+pub struct SeqLock<T> {
+    /// Even: no writer active.
+    /// Odd: a writer is updating the data.
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+/// Types that can be copied byte by byte as plain integers.
+///
+/// # Safety
+///
+/// Every byte of the type must be initialized, so it can't have
+/// any padding, and (for `read` to never return garbage) every byte
+/// pattern that's written must be valid when read back whole.
+pub unsafe trait NoUninit: Copy {}
+
+macro_rules! impl_no_uninit {
+    ($($t:ty),*) => {
+        $(unsafe impl NoUninit for $t {})*
+    };
+}
+
+impl_no_uninit!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+unsafe impl<T> Sync for SeqLock<T> where T: NoUninit + Send {}
+
+impl<T: NoUninit> SeqLock<T> {
+    #[allow(dead_code)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn read(&self) -> T {
+        loop {
+            let s1 = self.seq.load(Acquire);
+            if s1 % 2 == 1 {
+                // A writer is busy, the copy would be torn anyway.
+                std::hint::spin_loop();
+                continue;
+            }
+            // Safety: Only atomic operations touch the data.
+            let value = unsafe { self.copy_out() };
+            // Make sure the data loads above happen before the second load of `seq`.
+            fence(Acquire);
+            if self.seq.load(Relaxed) == s1 {
+                // Safety: No writer was active during the copy, so it's a whole `T`.
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let mut s = self.seq.load(Relaxed);
+        loop {
+            if s % 2 == 1 {
+                std::hint::spin_loop();
+                s = self.seq.load(Relaxed);
+                continue;
+            }
+            match self.seq.compare_exchange_weak(s, s + 1, Acquire, Relaxed) {
+                Ok(_) => break,
+                Err(e) => s = e,
+            }
+        }
+        // Readers that see any of our data stores must also see the odd sequence number.
+        fence(Release);
+        WriteGuard {
+            lock: self,
+            // Safety: We're the only writer, so nothing changes the data while we copy it.
+            value: unsafe { self.copy_out().assume_init() },
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Copies the data byte by byte using relaxed atomic loads.
+    unsafe fn copy_out(&self) -> MaybeUninit<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let src = self.data.get() as *mut u8;
+        let dst = value.as_mut_ptr() as *mut u8;
+        for i in 0..size_of::<T>() {
+            *dst.add(i) = AtomicU8::from_ptr(src.add(i)).load(Relaxed);
+        }
+        value
+    }
+
+    /// Copies `value` into the data byte by byte using relaxed atomic stores.
+    unsafe fn copy_in(&self, value: &T) {
+        let src = value as *const T as *const u8;
+        let dst = self.data.get() as *mut u8;
+        for i in 0..size_of::<T>() {
+            AtomicU8::from_ptr(dst.add(i)).store(*src.add(i), Relaxed);
+        }
+    }
+}
+
+/// Gives mutable access to a private copy of the data,
+/// which is published to readers when the guard is dropped.
+pub struct WriteGuard<'a, T: NoUninit> {
+    lock: &'a SeqLock<T>,
+    value: T,
+}
+
+impl<T: NoUninit> Deref for WriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: NoUninit> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: NoUninit> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: We hold the write lock.
+        unsafe { self.lock.copy_in(&self.value) };
+        // Back to even, publishing the new data.
+        let s = self.lock.seq.load(Relaxed);
+        self.lock.seq.store(s.wrapping_add(1), Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_seqlock() {
+        #[repr(C)]
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        struct Pair(u64, u64);
+
+        // Safety: Two `u64`s in a `repr(C)` struct, so no padding.
+        unsafe impl NoUninit for Pair {}
+
+        let lock = SeqLock::new(Pair(1, 2));
+        assert_eq!(lock.read(), Pair(1, 2));
+
+        let mut guard = lock.write();
+        guard.0 = 10;
+        // Readers don't see the change until the guard is dropped.
+        assert_eq!(guard.1, 2);
+        drop(guard);
+
+        assert_eq!(lock.read(), Pair(10, 2));
+    }
+
+    #[test]
+    fn test_seqlock_torn_reads() {
+        let lock = SeqLock::new([0u64; 16]);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Relaxed) {
+                        let data = lock.read();
+                        // Every element is written together, so a torn read
+                        // would show up as a mix of two values.
+                        assert!(data.iter().all(|&x| x == data[0]));
+                        // A single writer only ever moves forward.
+                        assert!(data[0] >= last);
+                        last = data[0];
+                    }
+                });
+            }
+            s.spawn(|| {
+                for i in 1..=100_000 {
+                    *lock.write() = [i; 16];
+                }
+                done.store(true, Relaxed);
+            });
+        });
+
+        assert_eq!(lock.read(), [100_000; 16]);
+    }
+}
@@ -0,0 +1,396 @@
+use super::atomic_waker::{AtomicWaker, ThreadWaker};
+use super::errors::{RecvError, SendError, TryRecvError};
+use super::iter::{Iter, Recv, TryIter};
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// An unbounded channel for any number of senders and a single receiver,
+/// which can be received from by an async task, or by a blocked thread.
+///
+/// Senders are plain threads, which push to the queue and wake the
+/// receiver's registered waker. A thread that blocks in `recv()` registers
+/// a waker that unparks it, so both cases use the same notification.
+struct Channel<T> {
+    queue: Mutex<VecDeque<T>>,
+    waker: AtomicWaker,
+    senders: AtomicUsize,
+    receiver_gone: AtomicBool,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+impl<T> Channel<T> {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<T>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[allow(dead_code)]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        queue: Mutex::new(VecDeque::new()),
+        waker: AtomicWaker::new(),
+        senders: AtomicUsize::new(1),
+        receiver_gone: AtomicBool::new(false),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Never blocks. Fails, giving the message back, if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.receiver_gone.load(Relaxed) {
+            return Err(SendError(message));
+        }
+        let mut queue = self.channel.lock();
+        queue.push_back(message);
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.sent(1, queue.len());
+        drop(queue);
+        self.channel.waker.wake();
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.lock().len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `None`, as the channel is unbounded.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        if self.channel.receiver_gone.load(Relaxed) {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, AcqRel) == 1 {
+            self.channel.waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Fails with `Disconnected` only once all `Sender`s are gone
+    /// and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(message) = self.pop() {
+            return Ok(message);
+        }
+        if self.channel.senders.load(Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // A sender might have sent something right before disconnecting.
+        self.pop().ok_or(TryRecvError::Disconnected)
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let message = self.channel.lock().pop_front();
+        #[cfg(feature = "channel_metrics")]
+        if message.is_some() {
+            self.channel.counters.received(1);
+        }
+        message
+    }
+
+    /// Like `Stream::poll_next`: `Ready(None)` means all `Sender`s are gone
+    /// and all messages they sent have been received.
+    ///
+    /// On `Pending`, the waker of `cx` is woken by the next message (or by
+    /// the last `Sender` going away).
+    #[allow(dead_code)]
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(message) => return Poll::Ready(Some(message)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.channel.waker.register(cx.waker());
+        // Check again, in case something happened before we registered.
+        match self.try_recv() {
+            Ok(message) => Poll::Ready(Some(message)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// For async code: `while let Some(message) = receiver.recv_async().await`.
+    #[allow(dead_code)]
+    pub fn recv_async(&mut self) -> impl Future<Output = Option<T>> + '_ {
+        poll_fn(|cx| self.poll_recv(cx))
+    }
+
+    /// Blocks the current thread until a message arrives,
+    /// using a waker that unparks this thread.
+    #[allow(dead_code)]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(message) = self.poll_recv(&mut cx) {
+                return message.ok_or(RecvError::Disconnected);
+            }
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            thread::park();
+            #[cfg(feature = "channel_metrics")]
+            self.channel.counters.recv_blocked(start.elapsed());
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.lock().len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `None`, as the channel is unbounded.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Blocks the current thread for every next message, like `recv()`, and ends
+    /// once all `Sender`s are gone and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn iter(&mut self) -> Iter<&mut Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&mut self) -> TryIter<&mut Self> {
+        TryIter::new(self)
+    }
+}
+
+impl<T> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> IntoIterator for &mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_gone.store(true, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::pin;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::task::Wake;
+    use std::time::Duration;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Runs a future to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_poll_recv() {
+        let (sender, mut receiver) = channel();
+        let count = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Pending);
+        sender.send(1).unwrap();
+        assert_eq!(count.0.load(Relaxed), 1);
+        // Sending again without polling in between doesn't wake again.
+        sender.send(2).unwrap();
+        assert_eq!(count.0.load(Relaxed), 1);
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(Some(1)));
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(Some(2)));
+
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Pending);
+        drop(sender);
+        assert_eq!(count.0.load(Relaxed), 2);
+        assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn test_async() {
+        let (sender, mut receiver) = channel();
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..100 {
+                        sender.send(t * 100 + i).unwrap();
+                    }
+                });
+            }
+            drop(sender);
+
+            let sum = block_on(async {
+                let mut sum = 0;
+                while let Some(i) = receiver.recv_async().await {
+                    sum += i;
+                }
+                sum
+            });
+            assert_eq!(sum, 399 * 400 / 2);
+        });
+    }
+
+    #[test]
+    fn test_blocking_recv() {
+        let (sender, mut receiver) = channel();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                sender.send("Wow").unwrap();
+            });
+            assert_eq!(receiver.recv(), Ok("Wow"));
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+
+    #[test]
+    fn test_iter() {
+        let (sender, mut receiver) = channel();
+
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(3).unwrap();
+            });
+            assert_eq!(receiver.into_iter().collect::<Vec<_>>(), [3]);
+        });
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (sender, receiver) = channel();
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 1));
+        drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+        assert_eq!(sender.receiver_count(), 0);
+    }
+}
@@ -0,0 +1,343 @@
+use super::atomic_waker::{AtomicWaker, ThreadWaker};
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// The channel from `safety_through_types.rs`, with a waker slot next to `ready`,
+/// so the `Receiver` can be awaited as well as blocked on.
+struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+    waker: AtomicWaker,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    fn len(&self) -> usize {
+        self.ready.load(Acquire) as usize
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.ready.get_mut() {
+            unsafe {
+                self.message.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        message: UnsafeCell::new(MaybeUninit::uninit()),
+        ready: AtomicBool::new(false),
+        waker: AtomicWaker::new(),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    #[allow(dead_code)]
+    pub fn send(self, message: T) {
+        unsafe {
+            (*self.channel.message.get()).write(message);
+        }
+        self.channel.ready.store(true, Release);
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.sent(1, 1);
+        self.channel.waker.wake();
+    }
+
+    /// Always 0, as sending consumes the sender.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    /// The `Arc` is only shared by the two ends, so the other one is there if it isn't unique.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        Arc::strong_count(&self.channel) - 1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    #[allow(dead_code)]
+    pub fn is_ready(&self) -> bool {
+        self.channel.ready.load(Acquire)
+    }
+
+    /// Blocks the current thread until the message arrives,
+    /// using a waker that unparks this thread.
+    #[allow(dead_code)]
+    pub fn receive(mut self) -> T {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(message) = self.poll_receive(&mut cx) {
+                return message;
+            }
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            thread::park();
+            #[cfg(feature = "channel_metrics")]
+            self.channel.counters.recv_blocked(start.elapsed());
+        }
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context) -> Poll<T> {
+        if !self.channel.ready.swap(false, Acquire) {
+            self.channel.waker.register(cx.waker());
+            // Check again, in case the message arrived before we registered.
+            if !self.channel.ready.swap(false, Acquire) {
+                return Poll::Pending;
+            }
+        }
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.received(1);
+        Poll::Ready(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+
+    /// 1 if the message is there, waiting to be received.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// 0 once the sender has sent its message or is dropped.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        Arc::strong_count(&self.channel) - 1
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        self.get_mut().poll_receive(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::Mutex;
+    use std::task::Wake;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+    /// A minimal single threaded executor: a queue of tasks,
+    /// where a task's waker puts its index back in the queue.
+    struct Executor {
+        tasks: Vec<Option<Task>>,
+        queue: Arc<Mutex<VecDeque<usize>>>,
+        polls: usize,
+    }
+
+    struct TaskWaker {
+        index: usize,
+        queue: Arc<Mutex<VecDeque<usize>>>,
+        thread: Thread,
+    }
+
+    impl Wake for TaskWaker {
+        fn wake(self: Arc<Self>) {
+            self.queue.lock().unwrap().push_back(self.index);
+            self.thread.unpark();
+        }
+    }
+
+    impl Executor {
+        fn new() -> Self {
+            Self {
+                tasks: Vec::new(),
+                queue: Arc::new(Mutex::new(VecDeque::new())),
+                polls: 0,
+            }
+        }
+
+        fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+            self.queue.lock().unwrap().push_back(self.tasks.len());
+            self.tasks.push(Some(Box::pin(task)));
+        }
+
+        fn run(&mut self) {
+            while self.tasks.iter().any(Option::is_some) {
+                let next = self.queue.lock().unwrap().pop_front();
+                let Some(index) = next else {
+                    thread::park();
+                    continue;
+                };
+                let Some(task) = &mut self.tasks[index] else {
+                    continue;
+                };
+                let waker = Waker::from(Arc::new(TaskWaker {
+                    index,
+                    queue: self.queue.clone(),
+                    thread: thread::current(),
+                }));
+                self.polls += 1;
+                if task
+                    .as_mut()
+                    .poll(&mut Context::from_waker(&waker))
+                    .is_ready()
+                {
+                    self.tasks[index] = None;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_await() {
+        let (sender, receiver) = channel();
+        let result = Arc::new(AtomicUsize::new(0));
+
+        let mut executor = Executor::new();
+        let r = result.clone();
+        executor.spawn(async move {
+            r.store(receiver.await, Relaxed);
+        });
+
+        thread::spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(123);
+        });
+
+        executor.run();
+        assert_eq!(result.load(Relaxed), 123);
+        // Once before the message arrived, once after it was woken.
+        assert_eq!(executor.polls, 2);
+    }
+
+    #[test]
+    fn test_many_tasks() {
+        let sum = Arc::new(AtomicUsize::new(0));
+        let mut executor = Executor::new();
+        let mut senders = Vec::new();
+
+        for _ in 0..10 {
+            let (sender, receiver) = channel();
+            senders.push(sender);
+            let sum = sum.clone();
+            executor.spawn(async move {
+                sum.fetch_add(receiver.await, Relaxed);
+            });
+        }
+
+        thread::spawn(move || {
+            for (i, sender) in senders.into_iter().enumerate() {
+                sender.send(i);
+            }
+        });
+
+        executor.run();
+        assert_eq!(sum.load(Relaxed), 45);
+    }
+
+    #[test]
+    fn test_blocking_receive() {
+        let (sender, receiver) = channel();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send("Wow");
+            });
+            assert_eq!(receiver.receive(), "Wow");
+        });
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, receiver) = channel();
+
+        assert_eq!((sender.capacity(), receiver.capacity()), (Some(1), Some(1)));
+        assert!(sender.is_empty());
+        assert_eq!((receiver.sender_count(), sender.receiver_count()), (1, 1));
+        sender.send(1);
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(receiver.sender_count(), 0);
+
+        let (sender, receiver) = channel::<i32>();
+        drop(receiver);
+        assert_eq!(sender.receiver_count(), 0);
+    }
+}
@@ -0,0 +1,158 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Release};
+use std::sync::Arc;
+use std::task::{Wake, Waker};
+use std::thread::Thread;
+
+/// Nobody is touching the waker.
+const WAITING: u32 = 0;
+/// The receiving side is storing a new waker.
+const REGISTERING: u32 = 0b01;
+/// The sending side is taking out the waker to wake it.
+const WAKING: u32 = 0b10;
+
+/// A slot for a single `Waker`, which one side registers and the other side wakes.
+///
+/// Registering and waking can happen at the same time. If they do, the waker
+/// being registered gets woken right away, so a wake up can never get lost.
+pub struct AtomicWaker {
+    state: AtomicU32,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Sync for AtomicWaker {}
+unsafe impl Send for AtomicWaker {}
+
+impl AtomicWaker {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Stores the waker to be woken by the next `wake()`.
+    ///
+    /// Only one thread may call this at a time.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAITING, REGISTERING, Acquire, Acquire)
+        {
+            Ok(_) => {
+                // Safety: The REGISTERING bit gives us exclusive access to the waker.
+                unsafe {
+                    match &mut *self.waker.get() {
+                        Some(old) if old.will_wake(waker) => {}
+                        slot => *slot = Some(waker.clone()),
+                    }
+                }
+                if let Err(actual) =
+                    self.state
+                        .compare_exchange(REGISTERING, WAITING, AcqRel, Acquire)
+                {
+                    // Someone called `wake()` while we were busy, and couldn't take the waker.
+                    debug_assert_eq!(actual, REGISTERING | WAKING);
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // A `wake()` is in progress, so whatever we were waiting for just happened.
+            Err(WAKING) => waker.wake_by_ref(),
+            Err(_) => {}
+        }
+    }
+
+    /// Takes the registered waker, if any, and wakes it.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, AcqRel) {
+            WAITING => {
+                // Safety: The WAKING bit gives us exclusive access to the waker.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Release);
+                waker
+            }
+            // Either someone else is waking, or `register()` is busy
+            // and will see our WAKING bit when it's done.
+            _ => None,
+        }
+    }
+}
+
+/// A waker that unparks a thread, for blocking on something that's otherwise awaited.
+pub struct ThreadWaker(pub Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_atomic_waker() {
+        let slot = AtomicWaker::new();
+        let count = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+
+        // Nothing registered yet.
+        slot.wake();
+        assert_eq!(count.0.load(Relaxed), 0);
+
+        slot.register(&waker);
+        slot.wake();
+        assert_eq!(count.0.load(Relaxed), 1);
+
+        // The waker was taken out, so it isn't woken again.
+        slot.wake();
+        assert_eq!(count.0.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_no_lost_wakeups() {
+        let slot = AtomicWaker::new();
+        let count = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..10000 {
+                    slot.register(&waker);
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..10000 {
+                    slot.wake();
+                }
+            });
+        });
+
+        // After the last register, there's either a wake, or the waker is still there.
+        slot.wake();
+        assert!(count.0.load(Relaxed) >= 1);
+    }
+}
@@ -1,5 +1,221 @@
+use super::errors::{RecvError, SendError, TryRecvError, TrySendError};
+use atomic_wait::{wait, wake_one};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// The lowest two bits of `Slot::state`.
+const EMPTY: u32 = 0;
+const WRITING: u32 = 1;
+const FULL: u32 = 2;
+const READING: u32 = 3;
+const STATE: u32 = 0b11;
+/// Set when one side is (about to start) waiting for the state to change.
+const WAITER: u32 = 0b100;
+/// Set when the `Sender` or `Receiver` is dropped. Cleared by `split()`.
+const CLOSED: u32 = 0b1000;
+
+/// Like the `Channel` below, but it can carry any number of messages, one at
+/// a time, and can be split again once the `Sender` and `Receiver` are gone.
+///
+/// A message goes from EMPTY to WRITING to FULL, and is then taken out by
+/// going to READING and back to EMPTY. Every step is a compare-and-exchange,
+/// so the slot can never be written while full, or read while empty, no
+/// matter how it's used. Blocking is a futex wait on the same state.
+pub struct Slot<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU32,
+}
+
+unsafe impl<T> Sync for Slot<T> where T: Send {}
+
+impl<T> Drop for Slot<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() & STATE == FULL {
+            unsafe { self.message.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Slot<T> {
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU32::new(EMPTY),
+        }
+    }
+
+    /// Unlike `Channel::split`, this keeps a message that wasn't received
+    /// yet, for the next `Receiver` to pick up.
+    #[allow(dead_code)]
+    pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
+        *self.state.get_mut() &= STATE;
+        (Sender { slot: self }, Receiver { slot: self })
+    }
+
+    /// Takes out a message that wasn't received, if there is one.
+    #[allow(dead_code)]
+    pub fn take(&mut self) -> Option<T> {
+        let state = self.state.get_mut();
+        if *state & STATE != FULL {
+            return None;
+        }
+        *state = EMPTY;
+        Some(unsafe { self.message.get_mut().assume_init_read() })
+    }
+
+    /// Moves from `from` to `to`, keeping the other bits. Fails if we're not in `from`.
+    fn transition(&self, from: u32, to: u32) -> bool {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s & STATE != from {
+                return false;
+            }
+            match self
+                .state
+                .compare_exchange_weak(s, s & !STATE | to, Acquire, Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+    }
+
+    /// Moves from WRITING or READING to `to`, waking up the other side if it's waiting.
+    fn finish(&self, to: u32) {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            match self
+                .state
+                .compare_exchange_weak(s, s & CLOSED | to, Release, Relaxed)
+            {
+                Ok(_) => break,
+                Err(e) => s = e,
+            }
+        }
+        if s & WAITER != 0 {
+            wake_one(&self.state);
+        }
+    }
+
+    /// Blocks until the state is no longer `s`, unless it changed already.
+    fn wait_while(&self, s: u32) {
+        // Only sleep once we've announced it, so whoever changes the state wakes us.
+        if self
+            .state
+            .compare_exchange(s, s | WAITER, Relaxed, Relaxed)
+            .is_ok()
+        {
+            wait(&self.state, s | WAITER);
+        }
+    }
+
+    fn close(&self) {
+        if self.state.fetch_or(CLOSED, Relaxed) & WAITER != 0 {
+            wake_one(&self.state);
+        }
+    }
+}
+
+pub struct Sender<'a, T> {
+    slot: &'a Slot<T>,
+}
+
+impl<T> Sender<'_, T> {
+    #[allow(dead_code)]
+    pub fn try_send(&mut self, message: T) -> Result<(), TrySendError<T>> {
+        if self.slot.state.load(Relaxed) & CLOSED != 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        if !self.slot.transition(EMPTY, WRITING) {
+            return Err(TrySendError::Full(message));
+        }
+        unsafe { (*self.slot.message.get()).write(message) };
+        self.slot.finish(FULL);
+        Ok(())
+    }
+
+    /// Blocks until the previous message was taken out. Fails if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(&mut self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            let s = self.slot.state.load(Relaxed);
+            if s & STATE != EMPTY && s & CLOSED == 0 {
+                self.slot.wait_while(s);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.slot.close();
+    }
+}
+
+pub struct Receiver<'a, T> {
+    slot: &'a Slot<T>,
+}
+
+impl<T> Receiver<'_, T> {
+    /// Fails with `Disconnected` only once the `Sender` is gone
+    /// and the last message it sent has been received.
+    #[allow(dead_code)]
+    pub fn try_receive(&mut self) -> Result<T, TryRecvError> {
+        if self.slot.transition(FULL, READING) {
+            let message = unsafe { (*self.slot.message.get()).assume_init_read() };
+            self.slot.finish(EMPTY);
+            return Ok(message);
+        }
+        if self.slot.state.load(Relaxed) & CLOSED == 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // The sender might have sent something right before disconnecting.
+        if self.slot.state.load(Acquire) & STATE == FULL {
+            return self.try_receive();
+        }
+        Err(TryRecvError::Disconnected)
+    }
+
+    /// Blocks until there's a message.
+    #[allow(dead_code)]
+    pub fn receive(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_receive() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let s = self.slot.state.load(Relaxed);
+            if s & STATE != FULL && s & CLOSED == 0 {
+                self.slot.wait_while(s);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        self.slot.close();
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::Slot;
     use std::cell::UnsafeCell;
     use std::mem::MaybeUninit;
     use std::sync::atomic::AtomicBool;
@@ -84,4 +300,55 @@ mod test {
             assert_eq!(receiver.receive(), "Wow");
         });
     }
+
+    #[test]
+    fn test_slot_ping_pong() {
+        let mut requests = Slot::new();
+        let mut responses = Slot::new();
+        let (mut request_sender, mut request_receiver) = requests.split();
+        let (mut response_sender, mut response_receiver) = responses.split();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                while let Ok(request) = request_receiver.receive() {
+                    response_sender.send(request * 2).unwrap();
+                }
+            });
+            for i in 0..1000 {
+                request_sender.send(i).unwrap();
+                assert_eq!(response_receiver.receive(), Ok(i * 2));
+            }
+            drop(request_sender);
+        });
+    }
+
+    #[test]
+    fn test_slot_reuse() {
+        use super::super::errors::{RecvError, SendError, TryRecvError, TrySendError};
+
+        let mut slot = Slot::new();
+        {
+            let (mut sender, mut receiver) = slot.split();
+            sender.send(1).unwrap();
+            assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+            assert_eq!(receiver.try_receive(), Ok(1));
+            assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+            sender.send(3).unwrap();
+            drop(sender);
+            // The message sent before disconnecting is still received.
+            assert_eq!(receiver.receive(), Ok(3));
+            assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+        }
+        {
+            let (mut sender, receiver) = slot.split();
+            sender.send(4).unwrap();
+            drop(receiver);
+            assert_eq!(sender.send(5), Err(SendError(5)));
+        }
+        // A message left behind is kept for the next split.
+        let (_, mut receiver) = slot.split();
+        assert_eq!(receiver.try_receive(), Ok(4));
+        drop(receiver);
+        assert_eq!(slot.take(), None);
+    }
 }
@@ -0,0 +1,651 @@
+use super::errors::{self, SendError};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::SelectRecv;
+use super::waiters::Waiters;
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_all};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell so far behind that this many messages were overwritten
+    /// before it got to them. The next receive continues with the oldest message left.
+    Lagged(u64),
+    /// The sender is gone, and there are no messages left.
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Lagged(u64),
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Lagged(u64),
+    Disconnected,
+}
+
+struct Slot<T> {
+    /// The position of the message in this slot.
+    pos: u64,
+    /// Only `None` if nothing has been sent in this slot yet.
+    message: Option<T>,
+}
+
+/// A bounded channel where every receiver gets every message.
+///
+/// The sender never waits for slow receivers. It just overwrites the oldest
+/// message, and a receiver that still wanted it finds out from the position
+/// stored next to the message. Every slot has its own lock, so receivers can
+/// clone messages out of different slots at the same time.
+///
+/// Receivers wait on `seq`, a counter that's bumped after every message,
+/// and when the sender goes away.
+struct Channel<T> {
+    slots: Box<[RwLock<Slot<T>>]>,
+    /// The position of the next message.
+    tail: AtomicU64,
+    seq: AtomicU32,
+    /// The number of receivers that are (about to start) waiting on `seq`.
+    sleepers: AtomicU32,
+    /// Only for receivers in a `Select`, which can't wait on `seq`.
+    selectors: Waiters,
+    receivers: AtomicUsize,
+    disconnected: AtomicBool,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+impl<T> Channel<T> {
+    fn slot(&self, pos: u64) -> &RwLock<Slot<T>> {
+        // The number of slots is a power of two.
+        &self.slots[pos as usize & (self.slots.len() - 1)]
+    }
+
+    fn notify(&self) {
+        self.seq.fetch_add(1, Release);
+        // Pairs with the fence in `recv_until()`: either we see them in `sleepers`,
+        // or their futex wait sees the new `seq`.
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            wake_all(&self.seq);
+        }
+        self.selectors.notify_selectors();
+    }
+
+    /// The number of messages still kept around, whether anyone wants them or not.
+    fn retained(&self, tail: u64) -> usize {
+        tail.min(self.slots.len() as u64) as usize
+    }
+}
+
+/// Creates a channel that keeps the last `capacity` messages around
+/// for receivers that haven't seen them yet.
+///
+/// The capacity is rounded up to a power of two.
+#[allow(dead_code)]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let a = Arc::new(Channel {
+        slots: (0..capacity)
+            .map(|_| {
+                RwLock::new(Slot {
+                    pos: 0,
+                    message: None,
+                })
+            })
+            .collect(),
+        tail: AtomicU64::new(0),
+        seq: AtomicU32::new(0),
+        sleepers: AtomicU32::new(0),
+        selectors: Waiters::new(),
+        receivers: AtomicUsize::new(1),
+        disconnected: AtomicBool::new(false),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (
+        Sender {
+            channel: a.clone(),
+            tail: 0,
+        },
+        Receiver {
+            channel: a,
+            next: 0,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+    /// Our own copy of `channel.tail`, which only we modify.
+    tail: u64,
+}
+
+impl<T> Sender<T> {
+    /// The number of messages kept around for receivers that haven't seen them yet,
+    /// which is everything sent, up to the capacity.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.retained(self.tail)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.slots.len())
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    /// Every receiver counts towards `received`, and senders never block.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Sends a message to all current receivers, never blocking on slow ones.
+    /// Returns the number of receivers, or fails if there are none.
+    #[allow(dead_code)]
+    pub fn send(&mut self, message: T) -> Result<usize, SendError<T>> {
+        let c = &*self.channel;
+        let receivers = c.receivers.load(Relaxed);
+        if receivers == 0 {
+            return Err(SendError(message));
+        }
+        // Only a panic while writing poisons a slot, and nothing here can panic.
+        let mut slot = c.slot(self.tail).write().unwrap_or_else(|e| e.into_inner());
+        slot.pos = self.tail;
+        let overwritten = slot.message.replace(message);
+        drop(slot);
+        // Drop the old message only after unlocking.
+        drop(overwritten);
+        self.tail += 1;
+        c.tail.store(self.tail, Release);
+        #[cfg(feature = "channel_metrics")]
+        c.counters.sent(1, c.retained(self.tail));
+        c.notify();
+        Ok(receivers)
+    }
+
+    /// Creates a new receiver, which only gets messages sent after this call.
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Receiver {
+            channel: self.channel.clone(),
+            next: self.tail,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnected.store(true, Release);
+        self.channel.notify();
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    /// The position of the next message we'll receive.
+    next: u64,
+}
+
+impl<T> Receiver<T> {
+    /// The number of messages this receiver still gets, not counting ones
+    /// it already lost by lagging behind.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        let tail = self.channel.tail.load(Acquire);
+        // We might have already received a message before `tail` was bumped for it.
+        tail.saturating_sub(self.next)
+            .min(self.channel.slots.len() as u64) as usize
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.slots.len())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        if self.channel.disconnected.load(Relaxed) {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    /// Every receiver counts towards `received`, and senders never block.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Fails with `Disconnected` only once the `Sender` is gone
+    /// and we've received everything it sent.
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let c = &*self.channel;
+        // Checked before looking at the slot, since the sender
+        // doesn't send anything anymore once this is set.
+        let disconnected = c.disconnected.load(Acquire);
+        let slot = c.slot(self.next).read().unwrap_or_else(|e| e.into_inner());
+        match &slot.message {
+            Some(message) if slot.pos == self.next => {
+                let message = message.clone();
+                drop(slot);
+                self.next += 1;
+                #[cfg(feature = "channel_metrics")]
+                c.counters.received(1);
+                Ok(message)
+            }
+            Some(_) if slot.pos > self.next => {
+                // Our message was overwritten. Skip to the oldest one that's still there,
+                // which is at least the one after the one we just saw, a round earlier.
+                let capacity = c.slots.len() as u64;
+                let seen = slot.pos;
+                drop(slot);
+                let oldest = c
+                    .tail
+                    .load(Acquire)
+                    .saturating_sub(capacity)
+                    .max(seen + 1 - capacity);
+                let missed = oldest - self.next;
+                self.next = oldest;
+                Err(TryRecvError::Lagged(missed))
+            }
+            // Nothing sent in this slot for this round yet.
+            _ if disconnected => Err(TryRecvError::Disconnected),
+            _ => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Blocks until there's a new message.
+    #[allow(dead_code)]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|e| match e {
+            RecvTimeoutError::Lagged(n) => RecvError::Lagged(n),
+            _ => RecvError::Disconnected,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let c = self.channel.clone();
+        loop {
+            // Loaded before checking for a message, so the futex wait
+            // returns right away if anything was sent since.
+            let seq = c.seq.load(Acquire);
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Lagged(n)) => return Err(RecvTimeoutError::Lagged(n)),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            c.sleepers.fetch_add(1, Relaxed);
+            fence(SeqCst);
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            match deadline {
+                None => wait(&c.seq, seq),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        c.sleepers.fetch_sub(1, Relaxed);
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    wait_timeout(&c.seq, seq, deadline - now);
+                }
+            }
+            #[cfg(feature = "channel_metrics")]
+            c.counters.recv_blocked(start.elapsed());
+            c.sleepers.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// Ends once the `Sender` is gone and we've received everything it sent.
+    ///
+    /// Messages lost by lagging behind are skipped silently.
+    /// Use `recv()` to find out about them.
+    #[allow(dead_code)]
+    pub fn iter(&mut self) -> Iter<&mut Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&mut self) -> TryIter<&mut Self> {
+        TryIter::new(self)
+    }
+}
+
+/// Skips over lost messages, as there's no way to report them.
+impl<T: Clone> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, errors::RecvError> {
+        loop {
+            match Receiver::recv(self) {
+                Ok(message) => return Ok(message),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Disconnected) => return Err(errors::RecvError::Disconnected),
+            }
+        }
+    }
+
+    fn try_recv(&mut self) -> Result<T, errors::TryRecvError> {
+        loop {
+            match Receiver::try_recv(self) {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) => return Err(errors::TryRecvError::Empty),
+                Err(TryRecvError::Disconnected) => return Err(errors::TryRecvError::Disconnected),
+            }
+        }
+    }
+}
+
+impl<T: Clone> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T: Clone> IntoIterator for &mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+/// Works with `Select`, but not with `select!`, which doesn't know about `Lagged`.
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        // Also true if we lagged behind, which `try_recv()` reports too.
+        self.channel.tail.load(Relaxed) > self.next || self.channel.disconnected.load(Relaxed)
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.selectors
+    }
+}
+
+/// The clone continues from the same position.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.fetch_sub(1, AcqRel);
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Lagged(n) => write!(f, "receiver lagged behind by {n} messages"),
+            RecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Lagged(n) => RecvError::Lagged(*n).fmt(f),
+            TryRecvError::Disconnected => RecvError::Disconnected.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => f.write_str("timed out waiting for a message"),
+            RecvTimeoutError::Lagged(n) => RecvError::Lagged(*n).fmt(f),
+            RecvTimeoutError::Disconnected => RecvError::Disconnected.fmt(f),
+        }
+    }
+}
+
+impl Error for RecvError {}
+impl Error for TryRecvError {}
+impl Error for RecvTimeoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_broadcast() {
+        let (mut sender, receiver) = channel(16);
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let mut receiver = receiver.clone();
+                    s.spawn(move || {
+                        let mut received = Vec::new();
+                        while let Ok(i) = receiver.recv() {
+                            received.push(i);
+                        }
+                        received
+                    })
+                })
+                .collect();
+            drop(receiver);
+
+            for i in 0..10 {
+                assert_eq!(sender.send(i), Ok(4));
+                thread::sleep(Duration::from_millis(1));
+            }
+            drop(sender);
+
+            for h in handles {
+                // Everyone got everything.
+                assert_eq!(h.join().unwrap(), (0..10).collect::<Vec<_>>());
+            }
+        });
+    }
+
+    #[test]
+    fn test_lagged() {
+        let (mut sender, mut receiver) = channel(4);
+
+        for i in 0..10 {
+            sender.send(i).unwrap();
+        }
+        // 0 to 5 were overwritten.
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(6)));
+        for i in 6..10 {
+            assert_eq!(receiver.try_recv(), Ok(i));
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        sender.send(10).unwrap();
+        assert_eq!(receiver.recv(), Ok(10));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let (mut sender, mut receiver) = channel(4);
+
+        sender.send(1).unwrap();
+        let mut late = sender.subscribe();
+        let mut clone = receiver.clone();
+        sender.send(2).unwrap();
+
+        // A new subscriber starts from the tail, and a clone where the original is.
+        assert_eq!(late.try_recv(), Ok(2));
+        assert_eq!(clone.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(clone.try_recv(), Ok(2));
+        assert_eq!(late.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (mut sender, mut receiver) = channel(4);
+        let late = sender.subscribe();
+
+        assert_eq!(sender.capacity(), Some(4));
+        assert!(sender.is_empty());
+        for i in 0..3 {
+            sender.send(i).unwrap();
+        }
+        receiver.try_recv().unwrap();
+        // Every receiver has its own position.
+        assert_eq!((receiver.len(), late.len()), (2, 3));
+        for i in 3..10 {
+            sender.send(i).unwrap();
+        }
+        // Only as many as are still there.
+        assert_eq!((sender.len(), receiver.len()), (4, 4));
+
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 2));
+        drop(late);
+        drop(sender);
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (0, 1));
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (mut sender, mut receiver) = channel(4);
+
+        sender.send(1).unwrap();
+        drop(sender);
+        // Still gets the message sent before disconnecting.
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+
+        let (mut sender, receiver) = channel(4);
+        drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn test_iter() {
+        let (mut sender, mut receiver) = channel(4);
+
+        for i in 0..6 {
+            sender.send(i).unwrap();
+        }
+        // 0 and 1 were overwritten, and are skipped.
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [2, 3, 4, 5]);
+
+        sender.send(6).unwrap();
+        drop(sender);
+        assert_eq!(receiver.into_iter().collect::<Vec<_>>(), [6]);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (mut sender, mut receiver) = channel(4);
+
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(123).unwrap();
+            });
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(123));
+        });
+
+        // Too long to compute a deadline for, so it just waits.
+        sender.send(456).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::MAX), Ok(456));
+    }
+
+    #[test]
+    fn test_slow_receiver() {
+        const N: u64 = 100_000;
+        let (mut sender, mut receiver) = channel(8);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..N {
+                    sender.send(i).unwrap();
+                }
+            });
+
+            // Whatever we do get, we get in order, and we account for everything.
+            let mut expected = 0;
+            loop {
+                match receiver.recv() {
+                    Ok(i) => {
+                        assert_eq!(i, expected);
+                        expected += 1;
+                    }
+                    Err(RecvError::Lagged(n)) => expected += n,
+                    Err(RecvError::Disconnected) => break,
+                }
+            }
+            assert_eq!(expected, N);
+        });
+    }
+}
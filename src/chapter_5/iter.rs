@@ -0,0 +1,65 @@
+use super::errors::{RecvError, TryRecvError};
+
+/// The receiving end of a channel, as far as the iterators below are concerned.
+///
+/// Receivers that need `&mut self` to receive implement this for themselves,
+/// which covers `&mut Receiver` through the impl below. Receivers that
+/// only need `&self` also implement it for `&Receiver`.
+pub trait Recv {
+    type Item;
+
+    fn recv(&mut self) -> Result<Self::Item, RecvError>;
+
+    fn try_recv(&mut self) -> Result<Self::Item, TryRecvError>;
+}
+
+impl<R: Recv + ?Sized> Recv for &mut R {
+    type Item = R::Item;
+
+    fn recv(&mut self) -> Result<Self::Item, RecvError> {
+        (**self).recv()
+    }
+
+    fn try_recv(&mut self) -> Result<Self::Item, TryRecvError> {
+        (**self).try_recv()
+    }
+}
+
+/// Blocks for every next message, and ends once all senders are gone
+/// and all messages they sent have been received.
+pub struct Iter<R> {
+    receiver: R,
+}
+
+impl<R> Iter<R> {
+    pub fn new(receiver: R) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<R: Recv> Iterator for Iter<R> {
+    type Item = R::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Only yields the messages that are in the channel already, without blocking.
+pub struct TryIter<R> {
+    receiver: R,
+}
+
+impl<R> TryIter<R> {
+    pub fn new(receiver: R) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<R: Recv> Iterator for TryIter<R> {
+    type Item = R::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.try_recv().ok()
+    }
+}
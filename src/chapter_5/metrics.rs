@@ -0,0 +1,222 @@
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::time::Duration;
+
+// Throughput and backpressure counters for our channels.
+//
+// Every channel keeps one set of counters in its shared state, next to the
+// messages. They're only updated with relaxed atomics, so they can be read at
+// any time, from either end, without locking or disturbing the channel.
+
+/// The counters of a single channel.
+#[derive(Default)]
+pub struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    send_blocked_nanos: AtomicU64,
+    recv_blocked_nanos: AtomicU64,
+    high_water_mark: AtomicUsize,
+}
+
+impl Counters {
+    /// Like `default()`, but usable in a `const fn`.
+    pub const fn new() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            send_blocked_nanos: AtomicU64::new(0),
+            recv_blocked_nanos: AtomicU64::new(0),
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    /// `n` messages were sent, after which the channel held `len` messages.
+    pub fn sent(&self, n: usize, len: usize) {
+        self.sent.fetch_add(n as u64, Relaxed);
+        self.high_water_mark.fetch_max(len, Relaxed);
+    }
+
+    pub fn received(&self, n: usize) {
+        self.received.fetch_add(n as u64, Relaxed);
+    }
+
+    /// A sender had to wait for `time` for a receiver or a free slot.
+    pub fn send_blocked(&self, time: Duration) {
+        self.send_blocked_nanos
+            .fetch_add(time.as_nanos() as u64, Relaxed);
+    }
+
+    /// A receiver had to wait for `time` for a message.
+    pub fn recv_blocked(&self, time: Duration) {
+        self.recv_blocked_nanos
+            .fetch_add(time.as_nanos() as u64, Relaxed);
+    }
+
+    pub fn metrics(&self) -> ChannelMetrics {
+        ChannelMetrics {
+            sent: self.sent.load(Relaxed),
+            received: self.received.load(Relaxed),
+            send_blocked: Duration::from_nanos(self.send_blocked_nanos.load(Relaxed)),
+            recv_blocked: Duration::from_nanos(self.recv_blocked_nanos.load(Relaxed)),
+            high_water_mark: self.high_water_mark.load(Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the counters of a channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelMetrics {
+    pub sent: u64,
+    pub received: u64,
+    /// The total time senders spent blocked, summed over all of them.
+    pub send_blocked: Duration,
+    /// The total time receivers spent blocked, summed over all of them.
+    pub recv_blocked: Duration,
+    /// The most messages the channel has held at once.
+    pub high_water_mark: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(target_os = "linux")]
+    use super::super::shared_memory_spsc::SharedChannel;
+    use super::super::simple_mutex_based_channel::PriorityChannel;
+    use super::super::{
+        async_oneshot, broadcast, mpmc, mpsc, oneshot, rendezvous, spsc, timer, watch,
+    };
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_backpressure() {
+        let (sender, receiver) = mpmc::channel(2);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                for _ in 0..5 {
+                    receiver.recv().unwrap();
+                }
+            });
+            // Blocks until the receiver shows up.
+            for i in 0..5 {
+                sender.send(i).unwrap();
+            }
+        });
+
+        let metrics = receiver.metrics();
+        assert_eq!(metrics, sender.metrics());
+        assert_eq!((metrics.sent, metrics.received), (5, 5));
+        assert_eq!(metrics.high_water_mark, 2);
+        assert!(metrics.send_blocked >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_recv_blocked() {
+        let (mut sender, mut receiver) = spsc::channel(4);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                sender.send_all(0..3).unwrap();
+            });
+            let mut buffer = Vec::new();
+            while buffer.len() < 3 {
+                receiver.recv_many(&mut buffer, 3).unwrap();
+            }
+        });
+
+        let metrics = receiver.metrics();
+        assert_eq!((metrics.sent, metrics.received), (3, 3));
+        assert!(metrics.recv_blocked >= Duration::from_millis(40));
+        assert_eq!(metrics.send_blocked, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_all_flavors() {
+        let (sender, mut receiver) = mpsc::channel();
+        sender.send_all(0..10).unwrap();
+        receiver.recv_many(&mut Vec::new(), 4).unwrap();
+        let metrics = sender.metrics();
+        assert_eq!((metrics.sent, metrics.received), (10, 4));
+        assert_eq!(metrics.high_water_mark, 10);
+
+        let (sender, receiver) = rendezvous::channel();
+        thread::scope(|s| {
+            s.spawn(|| sender.send(1).unwrap());
+            receiver.recv().unwrap();
+        });
+        let metrics = receiver.metrics();
+        assert_eq!((metrics.sent, metrics.received), (1, 1));
+
+        let (mut sender, mut receiver) = broadcast::channel(2);
+        let mut late = sender.subscribe();
+        for i in 0..3 {
+            sender.send(i).unwrap();
+        }
+        receiver.try_recv().unwrap_err();
+        receiver.try_recv().unwrap();
+        late.try_recv().unwrap_err();
+        late.try_recv().unwrap();
+        let metrics = sender.metrics();
+        // Both receivers count.
+        assert_eq!((metrics.sent, metrics.received), (3, 2));
+        assert_eq!(metrics.high_water_mark, 2);
+
+        let (sender, mut receiver) = watch::channel(0);
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        receiver.changed().unwrap();
+        let metrics = receiver.metrics();
+        assert_eq!((metrics.sent, metrics.received), (2, 1));
+
+        let (sender, mut receiver) = oneshot::channel();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(1).unwrap();
+            });
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(1));
+        });
+        let metrics = receiver.metrics();
+        assert_eq!((metrics.sent, metrics.received), (1, 1));
+        assert!(metrics.recv_blocked >= Duration::from_millis(40));
+
+        let (sender, receiver) = async_oneshot::channel();
+        sender.send(1);
+        // Receiving consumes the receiver, so this is as far as we can look.
+        assert_eq!(receiver.metrics().sent, 1);
+
+        let ticks = timer::tick(Duration::from_millis(1));
+        ticks.recv().unwrap();
+        let metrics = ticks.metrics();
+        assert!(metrics.sent >= 1);
+        assert_eq!(metrics.received, 1);
+
+        #[cfg(target_os = "linux")]
+        {
+            let channel = SharedChannel::<u64>::new(4).unwrap();
+            let fd = channel.fd().try_clone_to_owned().unwrap();
+            let mut receiver = unsafe { SharedChannel::<u64>::from_fd(fd) }
+                .unwrap()
+                .into_receiver();
+            let mut sender = channel.into_sender();
+            sender.send(1).unwrap();
+            sender.send(2).unwrap();
+            receiver.recv().unwrap();
+            // Both ends see the same counters, as they would from two processes.
+            let metrics = receiver.metrics();
+            assert_eq!(metrics, sender.metrics());
+            assert_eq!((metrics.sent, metrics.received), (2, 1));
+            assert_eq!(metrics.high_water_mark, 2);
+        }
+
+        let channel: PriorityChannel<i32, 2> = PriorityChannel::new();
+        channel.send(1, 1);
+        channel.send(0, 2);
+        channel.receive();
+        let metrics = channel.metrics();
+        assert_eq!((metrics.sent, metrics.received), (2, 1));
+        assert_eq!(metrics.high_water_mark, 2);
+    }
+}
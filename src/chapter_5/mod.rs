@@ -1,6 +1,25 @@
+mod async_mpsc;
+mod async_oneshot;
+mod atomic_waker;
 mod blocking;
 mod borrowing_to_avoid_allocation;
+mod broadcast;
+mod errors;
+mod iter;
+#[cfg(feature = "channel_metrics")]
+mod metrics;
+mod mpmc;
+mod mpsc;
+mod oneshot;
+mod rendezvous;
 mod safety_through_runtime_checks;
 mod safety_through_types;
+mod select;
+#[cfg(target_os = "linux")]
+mod shared_memory_spsc;
 mod simple_mutex_based_channel;
+mod spsc;
+mod timer;
 mod unsafe_oneshot_channel;
+mod waiters;
+mod watch;
@@ -0,0 +1,796 @@
+use super::errors::{
+    RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::{SelectRecv, SelectSend};
+use super::waiters::Waiters;
+use crate::chapter_7::cache_padded::CachePadded;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+
+/// A slot in the buffer, with a stamp that says whose turn it is.
+///
+/// For the slot at index `i` (modulo the capacity), a stamp of `i` means it's
+/// empty and ready to be written by the sender that claims index `i`,
+/// and `i + 1` means it's full and ready to be read by the receiver that
+/// claims index `i`. After reading, the stamp becomes `i + capacity`,
+/// handing the slot to the sender of the next round.
+struct Slot<T> {
+    stamp: AtomicUsize,
+    message: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A bounded channel for any number of senders and receivers, based on
+/// Dmitry Vyukov's bounded MPMC queue.
+///
+/// Senders claim a slot by bumping `tail` and receivers by bumping `head`,
+/// so they only contend with their own side, and never through a lock.
+struct Channel<T> {
+    buffer: Box<[Slot<T>]>,
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    /// Senders waiting for a free slot.
+    send_waiters: Waiters,
+    /// Receivers waiting for a message.
+    recv_waiters: Waiters,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    fn slot(&self, index: usize) -> &Slot<T> {
+        // The buffer length is a power of two.
+        &self.buffer[index & (self.buffer.len() - 1)]
+    }
+
+    /// Doesn't wake up a receiver, so that can be done once for many messages.
+    fn push(&self, message: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Relaxed);
+        loop {
+            let slot = self.slot(tail);
+            let stamp = slot.stamp.load(Acquire);
+            match stamp.wrapping_sub(tail) as isize {
+                0 => match self.tail.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.message.get()).write(message) };
+                        slot.stamp.store(tail.wrapping_add(1), Release);
+                        #[cfg(feature = "channel_metrics")]
+                        self.counters.sent(1, self.len());
+                        return Ok(());
+                    }
+                    Err(t) => tail = t,
+                },
+                // The slot still holds the message from the previous round.
+                d if d < 0 => return Err(message),
+                // Another sender claimed this index already.
+                _ => tail = self.tail.load(Relaxed),
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Relaxed);
+        loop {
+            let slot = self.slot(head);
+            let stamp = slot.stamp.load(Acquire);
+            match stamp.wrapping_sub(head.wrapping_add(1)) as isize {
+                0 => match self.head.compare_exchange_weak(
+                    head,
+                    head.wrapping_add(1),
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        let message = unsafe { (*slot.message.get()).assume_init_read() };
+                        slot.stamp
+                            .store(head.wrapping_add(self.buffer.len()), Release);
+                        self.send_waiters.notify_one();
+                        #[cfg(feature = "channel_metrics")]
+                        self.counters.received(1);
+                        return Some(message);
+                    }
+                    Err(h) => head = h,
+                },
+                // The message for this index hasn't been written (yet).
+                d if d < 0 => return None,
+                // Another receiver took this index already.
+                _ => head = self.head.load(Relaxed),
+            }
+        }
+    }
+
+    /// Takes up to `max` consecutive messages at once, with a single bump of
+    /// `head`, and a single wake up of the senders. Returns how many were taken.
+    fn pop_many(&self, buffer: &mut Vec<T>, max: usize) -> usize {
+        let max = max.min(self.buffer.len());
+        // Reserve before claiming anything, so nothing can panic
+        // between claiming slots and handing them back to the senders.
+        buffer.reserve(max);
+        let mut head = self.head.load(Relaxed);
+        loop {
+            let ready = |i: usize| {
+                let index = head.wrapping_add(i);
+                self.slot(index).stamp.load(Acquire) == index.wrapping_add(1)
+            };
+            let n = (0..max).take_while(|&i| ready(i)).count();
+            if n == 0 {
+                let stamp = self.slot(head).stamp.load(Acquire);
+                if (stamp.wrapping_sub(head.wrapping_add(1)) as isize) < 0 {
+                    return 0;
+                }
+                // Another receiver took this index already.
+                head = self.head.load(Relaxed);
+                continue;
+            }
+            match self
+                .head
+                .compare_exchange_weak(head, head.wrapping_add(n), Relaxed, Relaxed)
+            {
+                Ok(_) => {
+                    for i in 0..n {
+                        let index = head.wrapping_add(i);
+                        let slot = self.slot(index);
+                        buffer.push(unsafe { (*slot.message.get()).assume_init_read() });
+                        slot.stamp
+                            .store(index.wrapping_add(self.buffer.len()), Release);
+                    }
+                    Self::notify(&self.send_waiters, n);
+                    #[cfg(feature = "channel_metrics")]
+                    self.counters.received(n);
+                    return n;
+                }
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Wakes up as many threads as can make progress after `n` pushes or pops.
+    fn notify(waiters: &Waiters, n: usize) {
+        match n {
+            0 => {}
+            1 => waiters.notify_one(),
+            _ => waiters.notify_all(),
+        }
+    }
+
+    /// `send_waiters.wait_until()`, counting the time blocked.
+    fn wait_for_room(&self, deadline: Option<Instant>, ready: impl Fn() -> bool) -> bool {
+        #[cfg(feature = "channel_metrics")]
+        let start = Instant::now();
+        let in_time = self.send_waiters.wait_until(deadline, ready);
+        #[cfg(feature = "channel_metrics")]
+        self.counters.send_blocked(start.elapsed());
+        in_time
+    }
+
+    /// `recv_waiters.wait_until()`, counting the time blocked.
+    fn wait_for_message(&self, deadline: Option<Instant>, ready: impl Fn() -> bool) -> bool {
+        #[cfg(feature = "channel_metrics")]
+        let start = Instant::now();
+        let in_time = self.recv_waiters.wait_until(deadline, ready);
+        #[cfg(feature = "channel_metrics")]
+        self.counters.recv_blocked(start.elapsed());
+        in_time
+    }
+
+    /// The number of messages in the buffer, which might be outdated by the time
+    /// it returns. Includes slots that senders have claimed, but not yet written.
+    fn len(&self) -> usize {
+        // With other threads moving both, we might still see `head` ahead of `tail`,
+        // even though we load it first.
+        let head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+        let n = tail.wrapping_sub(head);
+        if (n as isize) < 0 {
+            0
+        } else {
+            n.min(self.buffer.len())
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        let tail = self.tail.load(Relaxed);
+        self.slot(tail).stamp.load(Relaxed) == tail
+    }
+
+    fn has_message(&self) -> bool {
+        let head = self.head.load(Relaxed);
+        self.slot(head).stamp.load(Relaxed) == head.wrapping_add(1)
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            let slot = &mut self.buffer[head & (self.buffer.len() - 1)];
+            unsafe { slot.message.get_mut().assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// Creates a channel with room for at least `capacity` messages.
+///
+/// The capacity is rounded up to a power of two, and is at least two,
+/// as with a single slot, a full slot would look like an empty one of the next round.
+#[allow(dead_code)]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(2).next_power_of_two();
+    let a = Arc::new(Channel {
+        buffer: (0..capacity)
+            .map(|i| Slot {
+                stamp: AtomicUsize::new(i),
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect(),
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        send_waiters: Waiters::new(),
+        recv_waiters: Waiters::new(),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    #[allow(dead_code)]
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        if self.channel.receivers.load(Relaxed) == 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        self.channel.push(message).map_err(TrySendError::Full)?;
+        self.channel.recv_waiters.notify_one();
+        Ok(())
+    }
+
+    /// Blocks while the channel is full. Fails once all `Receiver`s are gone.
+    #[allow(dead_code)]
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.send_until(message, None)
+            .map_err(|e| SendError(e.into_inner()))
+    }
+
+    #[allow(dead_code)]
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(message, Instant::now().checked_add(timeout))
+    }
+
+    fn send_until(
+        &self,
+        mut message: T,
+        deadline: Option<Instant>,
+    ) -> Result<(), SendTimeoutError<T>> {
+        let c = &*self.channel;
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => {
+                    return Err(SendTimeoutError::Disconnected(m))
+                }
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            if !c.wait_for_room(deadline, || SelectSend::is_ready(self)) {
+                return Err(SendTimeoutError::Timeout(message));
+            }
+        }
+    }
+
+    /// Sends all of `messages`, blocking whenever the channel is full, but
+    /// only waking up receivers once per batch that fits. Fails with the
+    /// first message that couldn't be sent once all `Receiver`s are gone.
+    #[allow(dead_code)]
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, messages: I) -> Result<(), SendError<T>> {
+        let c = &*self.channel;
+        let mut messages = messages.into_iter();
+        let Some(mut message) = messages.next() else {
+            return Ok(());
+        };
+        loop {
+            if c.receivers.load(Relaxed) == 0 {
+                return Err(SendError(message));
+            }
+            let mut sent = 0;
+            loop {
+                match c.push(message) {
+                    Ok(()) => sent += 1,
+                    Err(m) => {
+                        message = m;
+                        break;
+                    }
+                }
+                match messages.next() {
+                    Some(m) => message = m,
+                    None => {
+                        Channel::<T>::notify(&c.recv_waiters, sent);
+                        return Ok(());
+                    }
+                }
+            }
+            Channel::<T>::notify(&c.recv_waiters, sent);
+            c.wait_for_room(None, || SelectSend::is_ready(self));
+        }
+    }
+}
+
+impl<T> SelectSend for Sender<T> {
+    fn is_ready(&self) -> bool {
+        self.channel.has_room() || self.channel.receivers.load(Relaxed) == 0
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.send_waiters
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, AcqRel) == 1 {
+            self.channel.recv_waiters.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Fails with `Disconnected` only once all `Sender`s are gone
+    /// and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(message) = self.channel.pop() {
+            return Ok(message);
+        }
+        if self.channel.senders.load(Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // A sender might have sent something right before disconnecting.
+        self.channel.pop().ok_or(TryRecvError::Disconnected)
+    }
+
+    /// Blocks while the channel is empty.
+    #[allow(dead_code)]
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError::Disconnected)
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let c = &*self.channel;
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            if !c.wait_for_message(deadline, || SelectRecv::is_ready(self)) {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// Blocks until there's at least one message, and then appends all that
+    /// are available to `buffer`, up to `max`, claiming them all at once.
+    /// Returns how many were received.
+    #[allow(dead_code)]
+    pub fn recv_many(&self, buffer: &mut Vec<T>, max: usize) -> Result<usize, RecvError> {
+        let c = &*self.channel;
+        if max == 0 {
+            return Ok(0);
+        }
+        loop {
+            let n = c.pop_many(buffer, max);
+            if n > 0 {
+                return Ok(n);
+            }
+            if c.senders.load(Acquire) == 0 {
+                // A sender might have sent something right before disconnecting.
+                return match c.pop_many(buffer, max) {
+                    0 => Err(RecvError::Disconnected),
+                    n => Ok(n),
+                };
+            }
+            c.wait_for_message(None, || SelectRecv::is_ready(self));
+        }
+    }
+
+    /// Ends once all `Sender`s are gone and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<&Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&self) -> TryIter<&Self> {
+        TryIter::new(self)
+    }
+}
+
+impl<T> Recv for &Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> IntoIterator for &Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.channel.has_message() || self.channel.senders.load(Relaxed) == 0
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.recv_waiters
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.channel.receivers.fetch_sub(1, AcqRel) == 1 {
+            self.channel.send_waiters.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn test_channel() {
+        let (sender, receiver) = channel(4);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..10 {
+                    sender.send(i).unwrap();
+                }
+            });
+            for i in 0..10 {
+                assert_eq!(receiver.recv(), Ok(i));
+            }
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+
+    #[test]
+    fn test_full_and_empty() {
+        let (sender, receiver) = channel(2);
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        sender.try_send(1).unwrap();
+        sender.try_send(2).unwrap();
+        assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        sender.try_send(3).unwrap();
+        assert_eq!(receiver.try_recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Ok(3));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, receiver) = channel::<i32>(3);
+
+        assert_eq!(sender.capacity(), Some(4));
+        assert!(receiver.is_empty());
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert_eq!(receiver.len(), 2);
+        receiver.recv().unwrap();
+        assert_eq!(sender.len(), 1);
+
+        let receiver2 = receiver.clone();
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 2));
+        drop(sender);
+        drop(receiver2);
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (0, 1));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let (sender, receiver) = channel(2);
+
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        sender.send(0).unwrap();
+        sender.send(1).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            sender.send_timeout(2, Duration::from_millis(50)),
+            Err(SendTimeoutError::Timeout(2))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(receiver.recv(), Ok(0));
+            });
+            // Gets in once the receiver makes room.
+            sender.send_timeout(2, Duration::MAX).unwrap();
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(1));
+        // A timeout that doesn't fit in an `Instant` is no timeout at all.
+        assert_eq!(receiver.recv_timeout(Duration::MAX), Ok(2));
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (sender, receiver) = channel(4);
+        let sender2 = sender.clone();
+        sender.send(1).unwrap();
+        drop(sender);
+        sender2.send(2).unwrap();
+        drop(sender2);
+        // Messages sent before disconnecting can still be received.
+        assert_eq!(receiver.recv(), Ok(1));
+        assert_eq!(receiver.recv(), Ok(2));
+        assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+
+        let (sender, receiver) = channel(2);
+        let receiver2 = receiver.clone();
+        sender.send(0).unwrap();
+        sender.send(1).unwrap();
+        drop(receiver);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                drop(receiver2);
+            });
+            // Blocks on the full channel until the last receiver is dropped.
+            assert_eq!(sender.send(2), Err(SendError(2)));
+        });
+        assert_eq!(sender.try_send(3), Err(TrySendError::Disconnected(3)));
+    }
+
+    #[test]
+    fn test_unreceived_messages_dropped() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let (sender, receiver) = channel(4);
+        for _ in 0..3 {
+            sender.send(DetectDrop).unwrap();
+        }
+        drop(receiver.recv());
+        assert_eq!(NUM_DROPS.load(Relaxed), 1);
+        drop(sender);
+        drop(receiver);
+        assert_eq!(NUM_DROPS.load(Relaxed), 3);
+    }
+
+    #[test]
+    fn test_many_senders_and_receivers() {
+        const SENDERS: usize = 4;
+        const RECEIVERS: usize = 4;
+        const N: usize = 10_000;
+        let (sender, receiver) = channel(16);
+
+        let received: Vec<Vec<usize>> = thread::scope(|s| {
+            for t in 0..SENDERS {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..N {
+                        sender.send(t * N + i).unwrap();
+                    }
+                });
+            }
+            drop(sender);
+            let handles: Vec<_> = (0..RECEIVERS)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    s.spawn(move || {
+                        let mut received = Vec::new();
+                        while let Ok(i) = receiver.recv() {
+                            received.push(i);
+                        }
+                        received
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut all = HashSet::new();
+        for r in &received {
+            // Messages from the same sender arrive in order.
+            for t in 0..SENDERS {
+                let from_t: Vec<_> = r.iter().filter(|&&i| i / N == t).collect();
+                assert!(from_t.windows(2).all(|w| w[0] < w[1]));
+            }
+            all.extend(r.iter().copied());
+        }
+        assert_eq!(all.len(), SENDERS * N);
+    }
+
+    #[test]
+    fn test_batches_and_iterators() {
+        let (sender, receiver) = channel(8);
+
+        sender.send_all(0..6).unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(receiver.recv_many(&mut buffer, 4), Ok(4));
+        assert_eq!(receiver.recv_many(&mut buffer, 100), Ok(2));
+        assert_eq!(buffer, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(receiver.try_iter().next(), None);
+
+        let receiver2 = receiver.clone();
+        let total: usize = thread::scope(|s| {
+            s.spawn(move || sender.send_all(0..1000).unwrap());
+            let batches = s.spawn(move || {
+                let mut buffer = Vec::new();
+                while receiver2.recv_many(&mut buffer, 16).is_ok() {}
+                buffer.into_iter().sum::<usize>()
+            });
+            // Ends once the sender is gone.
+            let sum: usize = receiver.into_iter().sum();
+            sum + batches.join().unwrap()
+        });
+        assert_eq!(total, 999 * 1000 / 2);
+
+        let (sender, receiver) = channel(2);
+        drop(receiver);
+        assert_eq!(sender.send_all([1, 2]), Err(SendError(1)));
+    }
+}
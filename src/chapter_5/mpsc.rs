@@ -0,0 +1,600 @@
+use super::errors::{RecvError, SendError, TryRecvError};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::SelectRecv;
+use super::waiters::Waiters;
+use crate::chapter_7::cache_padded::CachePadded;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+use std::sync::Arc;
+use std::thread;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// The number of message slots in a block.
+const BLOCK_CAP: usize = 31;
+/// An index advances by one per message, and by one extra per block,
+/// so `index % LAP == BLOCK_CAP` means the block is full and the next one is
+/// still being installed.
+const LAP: usize = BLOCK_CAP + 1;
+
+struct Slot<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    ready: AtomicBool,
+}
+
+struct Block<T> {
+    next: AtomicPtr<Block<T>>,
+    slots: [Slot<T>; BLOCK_CAP],
+}
+
+impl<T> Block<T> {
+    fn new() -> Box<Self> {
+        Box::new(Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            slots: std::array::from_fn(|_| Slot {
+                message: UnsafeCell::new(MaybeUninit::uninit()),
+                ready: AtomicBool::new(false),
+            }),
+        })
+    }
+}
+
+/// An unbounded channel for any number of senders and a single receiver,
+/// as a linked list of blocks of slots.
+///
+/// Senders claim a slot by bumping `tail`, so they only contend on that index,
+/// and only allocate once per `BLOCK_CAP` messages. The receiver walks the
+/// list behind them, and frees every block once it has read all its slots.
+/// Since a sender never touches a block again after marking its slot ready,
+/// nobody can be using the block at that point anymore.
+struct Channel<T> {
+    /// Only used by the receiver, and by `drop`.
+    head: CachePadded<Position<T>>,
+    tail: CachePadded<Position<T>>,
+    senders: AtomicUsize,
+    receiver_gone: AtomicBool,
+    recv_waiters: Waiters,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+    /// The messages are only behind pointers, so this makes us `Send` only if `T` is.
+    _marker: PhantomData<T>,
+}
+
+struct Position<T> {
+    index: AtomicUsize,
+    block: AtomicPtr<Block<T>>,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    /// Doesn't wake up the receiver, so that can be done once for many messages.
+    fn push(&self, message: T) {
+        let mut next_block = None;
+        let mut tail = self.tail.index.load(Acquire);
+        let mut block = self.tail.block.load(Acquire);
+        loop {
+            let offset = tail % LAP;
+            if offset == BLOCK_CAP {
+                // Another sender is installing the next block.
+                thread::yield_now();
+                tail = self.tail.index.load(Acquire);
+                block = self.tail.block.load(Acquire);
+                continue;
+            }
+            // Allocate the next block before claiming the last slot,
+            // to keep the time that other senders have to wait for it short.
+            if offset + 1 == BLOCK_CAP && next_block.is_none() {
+                next_block = Some(Block::new());
+            }
+            match self
+                .tail
+                .index
+                .compare_exchange_weak(tail, tail + 1, AcqRel, Acquire)
+            {
+                Ok(_) => unsafe {
+                    if offset + 1 == BLOCK_CAP {
+                        let next = Box::into_raw(next_block.unwrap());
+                        self.tail.block.store(next, Release);
+                        self.tail.index.fetch_add(1, Release);
+                        (*block).next.store(next, Release);
+                    }
+                    let slot = &(*block).slots[offset];
+                    (*slot.message.get()).write(message);
+                    slot.ready.store(true, Release);
+                    #[cfg(feature = "channel_metrics")]
+                    self.counters.sent(1, self.len());
+                    return;
+                },
+                Err(t) => {
+                    tail = t;
+                    block = self.tail.block.load(Acquire);
+                }
+            }
+        }
+    }
+
+    /// Only called by the receiver.
+    fn pop(&self) -> Option<T> {
+        let head = self.head.index.load(Relaxed);
+        let block = self.head.block.load(Relaxed);
+        let offset = head % LAP;
+        unsafe {
+            let slot = &(*block).slots[offset];
+            if !slot.ready.load(Acquire) {
+                return None;
+            }
+            let message = (*slot.message.get()).assume_init_read();
+            if offset + 1 == BLOCK_CAP {
+                // The sender of the last slot linked the next block before marking it ready.
+                let next = (*block).next.load(Acquire);
+                self.head.block.store(next, Relaxed);
+                self.head.index.store(head + 2, Relaxed);
+                drop(Box::from_raw(block));
+            } else {
+                self.head.index.store(head + 1, Relaxed);
+            }
+            #[cfg(feature = "channel_metrics")]
+            self.counters.received(1);
+            Some(message)
+        }
+    }
+
+    /// The number of messages in the channel, which might be outdated by the time
+    /// it returns. Includes slots that senders have claimed, but not yet written.
+    fn len(&self) -> usize {
+        // Every full block adds one extra to the index.
+        fn messages(index: usize) -> usize {
+            index - index / LAP
+        }
+        // Loading `head` first makes it unlikely to be ahead of `tail`, but with
+        // relaxed loads of two different atomics, nothing guarantees that.
+        let head = self.head.index.load(Relaxed);
+        let tail = self.tail.index.load(Relaxed);
+        messages(tail).saturating_sub(messages(head))
+    }
+
+    fn has_message(&self) -> bool {
+        let head = self.head.index.load(Relaxed);
+        let block = self.head.block.load(Relaxed);
+        unsafe { (*block).slots[head % LAP].ready.load(Relaxed) }
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.index.get_mut();
+        let mut head = *self.head.index.get_mut();
+        let mut block = *self.head.block.get_mut();
+        unsafe {
+            while head != tail {
+                let offset = head % LAP;
+                if offset == BLOCK_CAP {
+                    let next = *(*block).next.get_mut();
+                    drop(Box::from_raw(block));
+                    block = next;
+                } else {
+                    (*block).slots[offset].message.get_mut().assume_init_drop();
+                }
+                head += 1;
+            }
+            drop(Box::from_raw(block));
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let block = Box::into_raw(Block::new());
+    let a = Arc::new(Channel {
+        head: CachePadded(Position {
+            index: AtomicUsize::new(0),
+            block: AtomicPtr::new(block),
+        }),
+        tail: CachePadded(Position {
+            index: AtomicUsize::new(0),
+            block: AtomicPtr::new(block),
+        }),
+        senders: AtomicUsize::new(1),
+        receiver_gone: AtomicBool::new(false),
+        recv_waiters: Waiters::new(),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+        _marker: PhantomData,
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `None`, as the channel is unbounded.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        if self.channel.receiver_gone.load(Relaxed) {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Never blocks. Fails, giving the message back, if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        if self.channel.receiver_gone.load(Relaxed) {
+            return Err(SendError(message));
+        }
+        self.channel.push(message);
+        self.channel.recv_waiters.notify_one();
+        Ok(())
+    }
+
+    /// Sends all of `messages`, only waking up the receiver once at the end.
+    /// Fails with the first message that couldn't be sent if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send_all<I: IntoIterator<Item = T>>(&self, messages: I) -> Result<(), SendError<T>> {
+        let mut result = Ok(());
+        let mut sent = false;
+        for message in messages {
+            if self.channel.receiver_gone.load(Relaxed) {
+                result = Err(SendError(message));
+                break;
+            }
+            self.channel.push(message);
+            sent = true;
+        }
+        if sent {
+            self.channel.recv_waiters.notify_one();
+        }
+        result
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, AcqRel) == 1 {
+            self.channel.recv_waiters.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `None`, as the channel is unbounded.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Fails with `Disconnected` only once all `Sender`s are gone
+    /// and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if let Some(message) = self.channel.pop() {
+            return Ok(message);
+        }
+        if self.channel.senders.load(Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // A sender might have sent something right before disconnecting.
+        self.channel.pop().ok_or(TryRecvError::Disconnected)
+    }
+
+    /// Blocks while the channel is empty.
+    #[allow(dead_code)]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            self.channel
+                .recv_waiters
+                .wait_until(None, || SelectRecv::is_ready(self));
+            #[cfg(feature = "channel_metrics")]
+            self.channel.counters.recv_blocked(start.elapsed());
+        }
+    }
+
+    /// Blocks until there's at least one message, and then appends all that
+    /// are available to `buffer`, up to `max`. Returns how many were received.
+    #[allow(dead_code)]
+    pub fn recv_many(&mut self, buffer: &mut Vec<T>, max: usize) -> Result<usize, RecvError> {
+        if max == 0 {
+            return Ok(0);
+        }
+        buffer.push(self.recv()?);
+        let mut n = 1;
+        while n < max {
+            let Some(message) = self.channel.pop() else {
+                break;
+            };
+            buffer.push(message);
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Ends once all `Sender`s are gone and all messages they sent have been received.
+    #[allow(dead_code)]
+    pub fn iter(&mut self) -> Iter<&mut Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&mut self) -> TryIter<&mut Self> {
+        TryIter::new(self)
+    }
+}
+
+impl<T> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> IntoIterator for &mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.channel.has_message() || self.channel.senders.load(Relaxed) == 0
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.recv_waiters
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receiver_gone.store(true, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_channel() {
+        let (sender, mut receiver) = channel();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                for i in 0..100 {
+                    sender.send(i).unwrap();
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(receiver.recv(), Ok(i));
+            }
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, mut receiver) = channel();
+
+        assert_eq!(sender.capacity(), None);
+        assert!(receiver.is_empty());
+        // Spans a few blocks.
+        for i in 0..100 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!(receiver.len(), 100);
+        for _ in 0..40 {
+            receiver.recv().unwrap();
+        }
+        assert_eq!(sender.len(), 60);
+
+        let sender2 = sender.clone();
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (2, 1));
+        drop(sender2);
+        drop(receiver);
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 0));
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (sender, mut receiver) = channel();
+        let sender2 = sender.clone();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        sender.send(1).unwrap();
+        drop(sender);
+        sender2.send(2).unwrap();
+        drop(sender2);
+        // Messages sent before disconnecting can still be received.
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.recv(), Ok(2));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert_eq!(sender.send(1), Err(SendError(1)));
+    }
+
+    #[test]
+    fn test_unreceived_messages_dropped() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        // Spread over a few blocks.
+        let (sender, mut receiver) = channel();
+        for _ in 0..100 {
+            sender.send(DetectDrop).unwrap();
+        }
+        for _ in 0..40 {
+            drop(receiver.recv());
+        }
+        assert_eq!(NUM_DROPS.load(Relaxed), 40);
+        drop(receiver);
+        assert_eq!(NUM_DROPS.load(Relaxed), 40);
+        drop(sender);
+        assert_eq!(NUM_DROPS.load(Relaxed), 100);
+    }
+
+    #[test]
+    fn test_many_senders() {
+        const SENDERS: usize = 4;
+        const N: usize = 10_000;
+        let (sender, mut receiver) = channel();
+
+        thread::scope(|s| {
+            for t in 0..SENDERS {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..N {
+                        sender.send((t, i)).unwrap();
+                    }
+                });
+            }
+            drop(sender);
+
+            let mut next = [0; SENDERS];
+            while let Ok((t, i)) = receiver.recv() {
+                // Messages from the same sender arrive in order.
+                assert_eq!(i, next[t]);
+                next[t] += 1;
+            }
+            assert_eq!(next, [N; SENDERS]);
+        });
+    }
+
+    #[test]
+    fn test_batches_and_iterators() {
+        let (sender, mut receiver) = channel();
+
+        sender.send_all(0..10).unwrap();
+        let mut buffer = Vec::new();
+        assert_eq!(receiver.recv_many(&mut buffer, 4), Ok(4));
+        assert_eq!(receiver.recv_many(&mut buffer, 100), Ok(6));
+        assert_eq!(buffer, (0..10).collect::<Vec<_>>());
+        assert_eq!(receiver.try_iter().next(), None);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    sender.send(i).unwrap();
+                }
+            });
+            // Ends once the sender is gone.
+            assert_eq!(receiver.iter().sum::<i32>(), 4950);
+        });
+
+        let (sender, receiver) = channel();
+        sender.send(1).unwrap();
+        drop(receiver);
+        assert_eq!(sender.send_all([2, 3]), Err(SendError(2)));
+    }
+}
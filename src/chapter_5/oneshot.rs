@@ -0,0 +1,447 @@
+use super::errors::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_one};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+
+/// Nothing happened yet.
+const EMPTY: u32 = 0;
+/// The message is there, waiting to be received.
+const READY: u32 = 1;
+/// The `Sender` was dropped without sending anything.
+const DISCONNECTED: u32 = 2;
+/// The `Receiver` was dropped.
+const RECEIVER_GONE: u32 = 3;
+/// The message has been received.
+const TAKEN: u32 = 4;
+
+/// A one-shot channel that, unlike the ones in `blocking.rs`, notices when
+/// the other side is gone. The receiver blocks on the `state` futex, so it
+/// can be woken up both by a message and by a disconnecting sender.
+pub struct Channel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU32,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU32::new(EMPTY),
+            #[cfg(feature = "channel_metrics")]
+            counters: Counters::new(),
+        }
+    }
+
+    /// 1 if the message is there, waiting to be received.
+    fn len(&self) -> usize {
+        (self.state.load(Relaxed) == READY) as usize
+    }
+
+    /// Borrows the channel for a single message, without allocating.
+    #[allow(dead_code)]
+    pub fn split(&mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
+        *self = Self::new();
+        (
+            Sender {
+                channel: ChannelRef::Borrowed(self),
+            },
+            Receiver {
+                channel: ChannelRef::Borrowed(self),
+            },
+        )
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe {
+                self.message.get_mut().assume_init_drop();
+            }
+        }
+    }
+}
+
+/// Creates a channel in its own allocation, shared by the sender and receiver.
+#[allow(dead_code)]
+pub fn channel<'a, T>() -> (Sender<'a, T>, Receiver<'a, T>) {
+    let a = Arc::new(Channel::new());
+    (
+        Sender {
+            channel: ChannelRef::Shared(a.clone()),
+        },
+        Receiver {
+            channel: ChannelRef::Shared(a),
+        },
+    )
+}
+
+enum ChannelRef<'a, T> {
+    Shared(Arc<Channel<T>>),
+    Borrowed(&'a Channel<T>),
+}
+
+impl<T> Deref for ChannelRef<'_, T> {
+    type Target = Channel<T>;
+    fn deref(&self) -> &Channel<T> {
+        match self {
+            ChannelRef::Shared(a) => a,
+            ChannelRef::Borrowed(c) => c,
+        }
+    }
+}
+
+pub struct Sender<'a, T> {
+    channel: ChannelRef<'a, T>,
+}
+
+impl<T> Sender<'_, T> {
+    /// Always 0, as sending consumes the sender.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        if self.channel.state.load(Relaxed) == RECEIVER_GONE {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Fails, giving the message back, if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        let c = &*self.channel;
+        if c.state.load(Relaxed) == RECEIVER_GONE {
+            return Err(SendError(message));
+        }
+        unsafe {
+            (*c.message.get()).write(message);
+        }
+        match c.state.compare_exchange(EMPTY, READY, Release, Relaxed) {
+            Ok(_) => {
+                #[cfg(feature = "channel_metrics")]
+                c.counters.sent(1, 1);
+                wake_one(&c.state);
+                Ok(())
+            }
+            // The receiver was dropped while we were writing the message,
+            // so nobody else is going to touch it.
+            Err(_) => Err(SendError(unsafe { (*c.message.get()).assume_init_read() })),
+        }
+    }
+}
+
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        // Only does something if we didn't send anything.
+        if self
+            .channel
+            .state
+            .compare_exchange(EMPTY, DISCONNECTED, Release, Relaxed)
+            .is_ok()
+        {
+            wake_one(&self.channel.state);
+        }
+    }
+}
+
+pub struct Receiver<'a, T> {
+    channel: ChannelRef<'a, T>,
+}
+
+impl<T> Receiver<'_, T> {
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// 1 until the sender has sent its message or is dropped.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        if self.channel.state.load(Relaxed) == EMPTY {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_ready(&self) -> bool {
+        self.channel.state.load(Relaxed) == READY
+    }
+
+    /// Blocks until there's a message, or the `Sender` is dropped.
+    #[allow(dead_code)]
+    pub fn recv(mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Err(TryRecvError::Empty) => {
+                    #[cfg(feature = "channel_metrics")]
+                    let start = Instant::now();
+                    wait(&self.channel.state, EMPTY);
+                    #[cfg(feature = "channel_metrics")]
+                    self.channel.counters.recv_blocked(start.elapsed());
+                }
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Ok(message) => return Ok(message),
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self
+            .channel
+            .state
+            .compare_exchange(READY, TAKEN, Acquire, Acquire)
+        {
+            Ok(_) => {
+                #[cfg(feature = "channel_metrics")]
+                self.channel.counters.received(1);
+                Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+            }
+            Err(EMPTY) => Err(TryRecvError::Empty),
+            // Either the sender is gone, or we already took the message.
+            Err(_) => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        // Too far in the future to represent: no deadline at all.
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            match self.try_recv() {
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Ok(message) => return Ok(message),
+            }
+            let now = Instant::now();
+            match deadline {
+                Some(deadline) if now >= deadline => return Err(RecvTimeoutError::Timeout),
+                Some(deadline) => wait_timeout(&self.channel.state, EMPTY, deadline - now),
+                None => wait(&self.channel.state, EMPTY),
+            }
+            #[cfg(feature = "channel_metrics")]
+            self.channel.counters.recv_blocked(now.elapsed());
+        }
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        if self.channel.state.swap(RECEIVER_GONE, AcqRel) == READY {
+            unsafe {
+                (*self.channel.message.get()).assume_init_drop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_channel() {
+        let (sender, receiver) = channel();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send("Wow").unwrap();
+            });
+            assert_eq!(receiver.recv(), Ok("Wow"));
+        });
+    }
+
+    #[test]
+    fn test_borrowed_channel() {
+        let mut channel = Channel::new();
+
+        for i in 0..3 {
+            let (sender, receiver) = channel.split();
+            thread::scope(|s| {
+                s.spawn(|| {
+                    sender.send(i).unwrap();
+                });
+                assert_eq!(receiver.recv(), Ok(i));
+            });
+        }
+    }
+
+    #[test]
+    fn test_sender_dropped() {
+        let (sender, receiver) = channel::<i32>();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                drop(sender);
+            });
+            // Doesn't block forever.
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+
+        let mut channel = Channel::<i32>::new();
+        let (sender, mut receiver) = channel.split();
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, receiver) = channel();
+
+        assert_eq!((sender.capacity(), receiver.capacity()), (Some(1), Some(1)));
+        assert!(sender.is_empty());
+        assert_eq!((receiver.sender_count(), sender.receiver_count()), (1, 1));
+        sender.send(1).unwrap();
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(receiver.sender_count(), 0);
+
+        let (sender, receiver) = channel::<i32>();
+        drop(receiver);
+        assert_eq!(sender.receiver_count(), 0);
+    }
+
+    #[test]
+    fn test_borrowed_message() {
+        let hello = String::from("Hello");
+        let (sender, receiver) = channel();
+
+        thread::scope(|s| {
+            s.spawn(|| sender.send(hello.as_str()).unwrap());
+            assert_eq!(receiver.recv(), Ok("Hello"));
+        });
+    }
+
+    #[test]
+    fn test_receiver_dropped() {
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert_eq!(sender.send(String::from("Hi")), Err(SendError("Hi".into())));
+    }
+
+    #[test]
+    fn test_try_recv_and_timeout() {
+        let (sender, mut receiver) = channel();
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(123).unwrap();
+            });
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(123));
+        });
+
+        // The message can only be received once.
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+        let (sender, mut receiver) = channel();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(456).unwrap();
+            });
+            assert_eq!(receiver.recv_timeout(Duration::MAX), Ok(456));
+        });
+    }
+
+    #[test]
+    fn test_unreceived_message_dropped() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let (sender, receiver) = channel();
+        sender.send(DetectDrop).unwrap();
+        assert_eq!(NUM_DROPS.load(Relaxed), 0);
+        drop(receiver);
+        assert_eq!(NUM_DROPS.load(Relaxed), 1);
+
+        let mut channel = Channel::new();
+        let (sender, receiver) = channel.split();
+        sender.send(DetectDrop).unwrap();
+        drop(receiver);
+        assert_eq!(NUM_DROPS.load(Relaxed), 2);
+    }
+}
@@ -0,0 +1,687 @@
+use super::errors::{
+    RecvError, RecvTimeoutError, SendError, SendTimeoutError, TryRecvError, TrySendError,
+};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::{SelectRecv, SelectSend};
+use super::waiters::Waiters;
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+
+/// Still waiting for the other side.
+const WAITING: u32 = 0;
+/// The other side took our message, or gave us theirs.
+const DONE: u32 = 1;
+/// All of the other side is gone.
+const DISCONNECTED: u32 = 2;
+
+/// A blocked `send` or `recv`, waiting for a thread on the other side.
+///
+/// The packet is in one of the queues while it's waiting. Whoever takes
+/// it out of the queue gets to touch `message`, and then hands it back
+/// to the waiting thread by setting `state` and unparking it.
+struct Packet<T> {
+    /// The message of a waiting sender, or the slot to put the message in for a waiting receiver.
+    message: UnsafeCell<Option<T>>,
+    state: AtomicU32,
+    thread: Thread,
+}
+
+unsafe impl<T> Sync for Packet<T> where T: Send {}
+
+impl<T> Packet<T> {
+    fn new(message: Option<T>) -> Arc<Self> {
+        Arc::new(Self {
+            message: UnsafeCell::new(message),
+            state: AtomicU32::new(WAITING),
+            thread: thread::current(),
+        })
+    }
+
+    /// Only for whoever took the packet out of the queue.
+    unsafe fn complete(&self, state: u32) {
+        self.state.store(state, Release);
+        self.thread.unpark();
+    }
+
+    /// Parks until the packet is completed. Returns `WAITING` if `deadline` passed first.
+    fn wait_until(&self, deadline: Option<Instant>) -> u32 {
+        loop {
+            let state = self.state.load(Acquire);
+            if state != WAITING {
+                return state;
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return WAITING;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+}
+
+struct Inner<T> {
+    waiting_senders: VecDeque<Arc<Packet<T>>>,
+    waiting_receivers: VecDeque<Arc<Packet<T>>>,
+    senders: usize,
+    receivers: usize,
+}
+
+/// A channel without any capacity: a `send` only completes once a `recv`
+/// takes the message directly from it, and the other way around.
+///
+/// Whichever side comes first puts a `Packet` in its queue, and parks until
+/// a thread of the other side comes along to exchange the message.
+///
+/// A thread in a `Select` doesn't queue a packet, it only waits for one to show
+/// up on the other side. So two threads selecting on opposite ends never meet:
+/// at least one of them has to really `send` or `recv`.
+struct Channel<T> {
+    inner: Mutex<Inner<T>>,
+    /// Notified when a receiver starts waiting, or the last one is gone.
+    send_selectors: Waiters,
+    /// Notified when a sender starts waiting, or the last one is gone.
+    recv_selectors: Waiters,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+impl<T> Channel<T> {
+    fn lock(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Removes our packet from the queue after a timeout. Returns false if
+    /// someone else took it out first, so we'll still have to wait for them.
+    fn cancel(&self, packet: &Arc<Packet<T>>, send: bool) -> bool {
+        let mut inner = self.lock();
+        let queue = if send {
+            &mut inner.waiting_senders
+        } else {
+            &mut inner.waiting_receivers
+        };
+        match queue.iter().position(|p| Arc::ptr_eq(p, packet)) {
+            Some(i) => {
+                queue.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Waits for our queued packet to be completed. Returns `WAITING` if
+    /// `deadline` passed, in which case the packet is no longer queued.
+    fn wait(&self, packet: &Arc<Packet<T>>, deadline: Option<Instant>, send: bool) -> u32 {
+        #[cfg(feature = "channel_metrics")]
+        let start = Instant::now();
+        let mut state = packet.wait_until(deadline);
+        if state == WAITING && !self.cancel(packet, send) {
+            state = packet.wait_until(None);
+        }
+        #[cfg(feature = "channel_metrics")]
+        if send {
+            self.counters.send_blocked(start.elapsed());
+        } else {
+            self.counters.recv_blocked(start.elapsed());
+        }
+        state
+    }
+
+    /// Counts a message that was handed over, while `waiting` senders were blocked.
+    #[cfg(feature = "channel_metrics")]
+    fn count_handoff(&self, waiting: usize) {
+        self.counters.sent(1, waiting);
+        self.counters.received(1);
+    }
+}
+
+#[allow(dead_code)]
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        inner: Mutex::new(Inner {
+            waiting_senders: VecDeque::new(),
+            waiting_receivers: VecDeque::new(),
+            senders: 1,
+            receivers: 1,
+        }),
+        send_selectors: Waiters::new(),
+        recv_selectors: Waiters::new(),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (Sender { channel: a.clone() }, Receiver { channel: a })
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// The number of senders blocked with a message, waiting for a receiver.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.lock().waiting_senders.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `Some(0)`, as messages are only ever handed over directly.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.lock().senders
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.lock().receivers
+    }
+
+    /// The high water mark is the most senders that were blocked at once.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Only succeeds if a receiver is already waiting.
+    #[allow(dead_code)]
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let mut inner = self.channel.lock();
+        if inner.receivers == 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        let Some(packet) = inner.waiting_receivers.pop_front() else {
+            return Err(TrySendError::Full(message));
+        };
+        #[cfg(feature = "channel_metrics")]
+        self.channel.count_handoff(inner.waiting_senders.len());
+        drop(inner);
+        unsafe {
+            *packet.message.get() = Some(message);
+            packet.complete(DONE);
+        }
+        Ok(())
+    }
+
+    /// Blocks until a receiver took the message.
+    #[allow(dead_code)]
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.send_until(message, None)
+            .map_err(|e| SendError(e.into_inner()))
+    }
+
+    #[allow(dead_code)]
+    pub fn send_timeout(&self, message: T, timeout: Duration) -> Result<(), SendTimeoutError<T>> {
+        self.send_until(message, Instant::now().checked_add(timeout))
+    }
+
+    fn send_until(&self, message: T, deadline: Option<Instant>) -> Result<(), SendTimeoutError<T>> {
+        let message = match self.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Disconnected(m)) => return Err(SendTimeoutError::Disconnected(m)),
+            Err(TrySendError::Full(m)) => m,
+        };
+        let packet = Packet::new(Some(message));
+        {
+            let mut inner = self.channel.lock();
+            // A receiver might have shown up since `try_send()`.
+            if let Some(receiver) = inner.waiting_receivers.pop_front() {
+                #[cfg(feature = "channel_metrics")]
+                self.channel.count_handoff(inner.waiting_senders.len());
+                drop(inner);
+                unsafe {
+                    *receiver.message.get() = (*packet.message.get()).take();
+                    receiver.complete(DONE);
+                }
+                return Ok(());
+            }
+            if inner.receivers == 0 {
+                let message = unsafe { (*packet.message.get()).take().unwrap() };
+                return Err(SendTimeoutError::Disconnected(message));
+            }
+            inner.waiting_senders.push_back(packet.clone());
+        }
+        self.channel.recv_selectors.notify_all();
+        match self.channel.wait(&packet, deadline, true) {
+            DONE => Ok(()),
+            state => {
+                let message = unsafe { (*packet.message.get()).take().unwrap() };
+                if state == WAITING {
+                    Err(SendTimeoutError::Timeout(message))
+                } else {
+                    Err(SendTimeoutError::Disconnected(message))
+                }
+            }
+        }
+    }
+}
+
+/// Ready once a receiver is waiting, so `try_send()` can hand the message over.
+impl<T> SelectSend for Sender<T> {
+    fn is_ready(&self) -> bool {
+        let inner = self.channel.lock();
+        !inner.waiting_receivers.is_empty() || inner.receivers == 0
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.send_selectors
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut inner = self.channel.lock();
+        inner.senders -= 1;
+        if inner.senders == 0 {
+            for packet in inner.waiting_receivers.drain(..) {
+                unsafe { packet.complete(DISCONNECTED) };
+            }
+            drop(inner);
+            self.channel.recv_selectors.notify_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The number of senders blocked with a message, waiting for a receiver.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.lock().waiting_senders.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Always `Some(0)`, as messages are only ever handed over directly.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.lock().senders
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.lock().receivers
+    }
+
+    /// The high water mark is the most senders that were blocked at once.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Only succeeds if a sender is already waiting.
+    #[allow(dead_code)]
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut inner = self.channel.lock();
+        let Some(packet) = inner.waiting_senders.pop_front() else {
+            return Err(if inner.senders == 0 {
+                TryRecvError::Disconnected
+            } else {
+                TryRecvError::Empty
+            });
+        };
+        #[cfg(feature = "channel_metrics")]
+        self.channel.count_handoff(inner.waiting_senders.len() + 1);
+        drop(inner);
+        unsafe {
+            let message = (*packet.message.get()).take().unwrap();
+            packet.complete(DONE);
+            Ok(message)
+        }
+    }
+
+    /// Blocks until a sender hands us a message.
+    #[allow(dead_code)]
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError::Disconnected)
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        match self.try_recv() {
+            Ok(message) => return Ok(message),
+            Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {}
+        }
+        let packet = Packet::new(None);
+        {
+            let mut inner = self.channel.lock();
+            // A sender might have shown up since `try_recv()`.
+            if let Some(sender) = inner.waiting_senders.pop_front() {
+                #[cfg(feature = "channel_metrics")]
+                self.channel.count_handoff(inner.waiting_senders.len() + 1);
+                drop(inner);
+                unsafe {
+                    let message = (*sender.message.get()).take().unwrap();
+                    sender.complete(DONE);
+                    return Ok(message);
+                }
+            }
+            if inner.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            inner.waiting_receivers.push_back(packet.clone());
+        }
+        self.channel.send_selectors.notify_all();
+        match self.channel.wait(&packet, deadline, false) {
+            DONE => Ok(unsafe { (*packet.message.get()).take().unwrap() }),
+            WAITING => Err(RecvTimeoutError::Timeout),
+            _ => Err(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    /// Ends once all `Sender`s are gone.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<&Self> {
+        Iter::new(self)
+    }
+
+    /// Only yields the messages of senders that are already waiting.
+    #[allow(dead_code)]
+    pub fn try_iter(&self) -> TryIter<&Self> {
+        TryIter::new(self)
+    }
+}
+
+impl<T> Recv for &Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> IntoIterator for &Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+/// Ready once a sender is waiting, so `try_recv()` can take its message.
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        let inner = self.channel.lock();
+        !inner.waiting_senders.is_empty() || inner.senders == 0
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.recv_selectors
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().receivers += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.channel.lock();
+        inner.receivers -= 1;
+        if inner.receivers == 0 {
+            for packet in inner.waiting_senders.drain(..) {
+                unsafe { packet.complete(DISCONNECTED) };
+            }
+            drop(inner);
+            self.channel.send_selectors.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    #[test]
+    fn test_rendezvous() {
+        let (sender, receiver) = channel();
+        let received = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                received.store(true, Relaxed);
+                assert_eq!(receiver.recv(), Ok("Wow"));
+            });
+            sender.send("Wow").unwrap();
+            // We can only get here once the receiver showed up.
+            assert!(received.load(Relaxed));
+        });
+    }
+
+    #[test]
+    fn test_iter() {
+        let (sender, receiver) = channel();
+
+        assert_eq!(receiver.try_iter().next(), None);
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..3 {
+                    sender.send(i).unwrap();
+                }
+            });
+            assert_eq!(receiver.iter().collect::<Vec<_>>(), [0, 1, 2]);
+        });
+    }
+
+    #[test]
+    fn test_try() {
+        let (sender, receiver) = channel();
+
+        // Nobody's waiting on the other side.
+        assert_eq!(sender.try_send(1), Err(TrySendError::Full(1)));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        thread::scope(|s| {
+            s.spawn(|| sender.send(2).unwrap());
+            loop {
+                match receiver.try_recv() {
+                    Ok(m) => break assert_eq!(m, 2),
+                    Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, receiver) = channel();
+
+        assert_eq!(sender.capacity(), Some(0));
+        assert!(receiver.is_empty());
+        thread::scope(|s| {
+            s.spawn(|| sender.send(1).unwrap());
+            while receiver.is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(receiver.len(), 1);
+            assert_eq!(receiver.recv(), Ok(1));
+        });
+        assert_eq!(sender.len(), 0);
+
+        let receiver2 = receiver.clone();
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 2));
+        drop(sender);
+        drop(receiver2);
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (0, 1));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let (sender, receiver) = channel();
+
+        let start = Instant::now();
+        assert_eq!(
+            sender.send_timeout(1, Duration::from_millis(50)),
+            Err(SendTimeoutError::Timeout(1))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The timed out sender didn't leave its message behind.
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(2).unwrap();
+            });
+            assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(2));
+        });
+
+        // A timeout past what an `Instant` can hold means no timeout.
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(receiver.recv(), Ok(3));
+            });
+            sender.send_timeout(3, Duration::MAX).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (sender, receiver) = channel::<i32>();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                drop(sender);
+            });
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+
+        let (sender, receiver) = channel();
+        let receiver2 = receiver.clone();
+        drop(receiver);
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                drop(receiver2);
+            });
+            assert_eq!(sender.send(1), Err(SendError(1)));
+        });
+        assert_eq!(sender.try_send(2), Err(TrySendError::Disconnected(2)));
+    }
+
+    #[test]
+    fn test_many_senders_and_receivers() {
+        const N: usize = 1000;
+        let (sender, receiver) = channel();
+
+        let total: usize = thread::scope(|s| {
+            for _ in 0..4 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..N {
+                        sender.send(i).unwrap();
+                    }
+                });
+            }
+            drop(sender);
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    s.spawn(move || {
+                        let mut sum = 0;
+                        loop {
+                            // Mix in timeouts, which can race with a sender showing up.
+                            match receiver.recv_timeout(Duration::from_micros(100)) {
+                                Ok(i) => sum += i,
+                                Err(RecvTimeoutError::Timeout) => {}
+                                Err(RecvTimeoutError::Disconnected) => break sum,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(total, 4 * N * (N - 1) / 2);
+    }
+}
@@ -1,5 +1,6 @@
 #[cfg(test)]
 mod test {
+    use super::super::errors::{TryRecvError, TrySendError};
     use std::cell::UnsafeCell;
     use std::mem::MaybeUninit;
     use std::sync::atomic::AtomicBool;
@@ -23,25 +24,41 @@ mod test {
             }
         }
 
-        pub fn send(&self, message: T) {
+        /// Fails with `Full`, giving the message back, if a message was already sent.
+        pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
             if self.in_use.swap(true, Relaxed) {
-                panic!("Can't send more than one more message!");
+                return Err(TrySendError::Full(message));
             }
             unsafe {
                 (*self.message.get()).write(message);
             }
             self.ready.store(true, Release);
+            Ok(())
+        }
+
+        pub fn send(&self, message: T) {
+            if self.try_send(message).is_err() {
+                panic!("Can't send more than one more message!");
+            }
         }
 
         pub fn is_ready(&self) -> bool {
             self.ready.load(Relaxed)
         }
 
+        /// Fails with `Empty` if nothing was sent, or if it was already received.
+        pub fn try_receive(&self) -> Result<T, TryRecvError> {
+            if !self.ready.swap(false, Acquire) {
+                return Err(TryRecvError::Empty);
+            }
+            Ok(unsafe { (*self.message.get()).assume_init_read() })
+        }
+
         pub fn receive(&self) -> T {
-            if !self.ready.load(Acquire) {
-                panic!("No Message available");
+            match self.try_receive() {
+                Ok(message) => message,
+                Err(_) => panic!("No Message available"),
             }
-            unsafe { (*self.message.get()).assume_init_read() }
         }
     }
 
@@ -70,4 +87,14 @@ mod test {
             });
         });
     }
+
+    #[test]
+    fn test_misuse() {
+        let channel = Channel::new();
+        assert_eq!(channel.try_receive(), Err(TryRecvError::Empty));
+        assert!(channel.try_send("a").is_ok());
+        assert_eq!(channel.try_send("b"), Err(TrySendError::Full("b")));
+        assert_eq!(channel.try_receive(), Ok("a"));
+        assert_eq!(channel.try_receive(), Err(TryRecvError::Empty));
+    }
 }
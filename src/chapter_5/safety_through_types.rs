@@ -1,5 +1,6 @@
 #[cfg(test)]
 mod test {
+    use super::super::errors::TryRecvError;
     use std::cell::UnsafeCell;
     use std::mem::MaybeUninit;
     use std::sync::atomic::AtomicBool;
@@ -29,11 +30,20 @@ mod test {
             self.channel.ready.load(Relaxed)
         }
 
-        pub fn receive(self) -> T {
+        /// Fails with `Empty`, keeping the receiver, if nothing was sent yet.
+        /// (Sending can't fail: there's only one `Sender`, and `send` consumes it.)
+        pub fn try_receive(&mut self) -> Result<T, TryRecvError> {
             if !self.channel.ready.swap(false, Acquire) {
-                panic!("No message available!");
+                return Err(TryRecvError::Empty);
+            }
+            Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+        }
+
+        pub fn receive(mut self) -> T {
+            match self.try_receive() {
+                Ok(message) => message,
+                Err(_) => panic!("No message available!"),
             }
-            unsafe { (*self.channel.message.get()).assume_init_read() }
         }
     }
 
@@ -82,4 +92,13 @@ mod test {
             assert_eq!(receiver.receive(), "Wow");
         });
     }
+
+    #[test]
+    fn test_try_receive() {
+        let (sender, mut receiver) = channel();
+        assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+        sender.send("Wow");
+        assert_eq!(receiver.try_receive(), Ok("Wow"));
+        assert_eq!(receiver.try_receive(), Err(TryRecvError::Empty));
+    }
 }
@@ -0,0 +1,599 @@
+use super::waiters::Waiters;
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_one};
+use std::cell::Cell;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicU32};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The receiving side of a channel that can be used in a `Select`.
+pub trait SelectRecv {
+    /// Whether `try_recv()` would return something other than `Empty` right now.
+    fn is_ready(&self) -> bool;
+    /// Where the channel notifies receivers.
+    fn waiters(&self) -> &Waiters;
+}
+
+/// The sending side of a channel that can be used in a `Select`.
+pub trait SelectSend {
+    /// Whether `try_send()` would return something other than `Full` right now.
+    fn is_ready(&self) -> bool;
+    /// Where the channel notifies senders.
+    fn waiters(&self) -> &Waiters;
+}
+
+// So `select!` can take channels that only need `&self`
+// as `recv(&receiver)`, without a `mut` binding.
+
+impl<R: SelectRecv + ?Sized> SelectRecv for &R {
+    fn is_ready(&self) -> bool {
+        (**self).is_ready()
+    }
+
+    fn waiters(&self) -> &Waiters {
+        (**self).waiters()
+    }
+}
+
+impl<S: SelectSend + ?Sized> SelectSend for &S {
+    fn is_ready(&self) -> bool {
+        (**self).is_ready()
+    }
+
+    fn waiters(&self) -> &Waiters {
+        (**self).waiters()
+    }
+}
+
+/// A futex a selecting thread sleeps on, notified by any of the channels it selects on.
+pub struct Signal {
+    notified: AtomicU32,
+}
+
+impl Signal {
+    fn new() -> Self {
+        Self {
+            notified: AtomicU32::new(0),
+        }
+    }
+
+    pub fn notify(&self) {
+        if self.notified.swap(1, Release) == 0 {
+            wake_one(&self.notified);
+        }
+    }
+
+    /// Returns false if `deadline` passed before we were notified.
+    fn wait_until(&self, deadline: Option<Instant>) -> bool {
+        while self.notified.load(Acquire) == 0 {
+            match deadline {
+                None => wait(&self.notified, 0),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    wait_timeout(&self.notified, 0, deadline - now);
+                }
+            }
+        }
+        true
+    }
+}
+
+enum Operation<'a> {
+    Recv(&'a dyn SelectRecv),
+    Send(&'a dyn SelectSend),
+}
+
+impl Operation<'_> {
+    fn is_ready(&self) -> bool {
+        match self {
+            Operation::Recv(r) => r.is_ready(),
+            Operation::Send(s) => s.is_ready(),
+        }
+    }
+
+    fn waiters(&self) -> &Waiters {
+        match self {
+            Operation::Recv(r) => r.waiters(),
+            Operation::Send(s) => s.waiters(),
+        }
+    }
+}
+
+/// Waits for the first of several channel operations to become ready.
+///
+/// Only tells which operation is ready, without performing it, so another
+/// thread could still get there first. `try_recv()` or `try_send()` can then
+/// fail with `Empty` or `Full`, after which it's fine to select again.
+/// The `select!` macro does all of that in a loop.
+#[derive(Default)]
+pub struct Select<'a> {
+    operations: Vec<Operation<'a>>,
+}
+
+thread_local! {
+    /// Where the next `try_ready()` on this thread starts looking,
+    /// so an always ready operation can't starve the others.
+    /// (Per thread, so selecting threads don't all contend on it.)
+    static NEXT_START: Cell<usize> = const { Cell::new(0) };
+}
+
+impl<'a> Select<'a> {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a receive operation, returning its index.
+    #[allow(dead_code)]
+    pub fn recv(&mut self, receiver: &'a dyn SelectRecv) -> usize {
+        self.operations.push(Operation::Recv(receiver));
+        self.operations.len() - 1
+    }
+
+    /// Adds a send operation, returning its index.
+    #[allow(dead_code)]
+    pub fn send(&mut self, sender: &'a dyn SelectSend) -> usize {
+        self.operations.push(Operation::Send(sender));
+        self.operations.len() - 1
+    }
+
+    /// The index of a ready operation, if there is one, without blocking.
+    #[allow(dead_code)]
+    pub fn try_ready(&self) -> Option<usize> {
+        let n = self.operations.len();
+        let start = NEXT_START.get();
+        NEXT_START.set(start.wrapping_add(1));
+        (0..n)
+            .map(|i| (start + i) % n)
+            .find(|&i| self.operations[i].is_ready())
+    }
+
+    /// Blocks until one of the operations is ready.
+    #[allow(dead_code)]
+    pub fn ready(&self) -> usize {
+        self.ready_until(None).unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn ready_timeout(&self, timeout: Duration) -> Option<usize> {
+        self.ready_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Like `ready_timeout`, but with a deadline, which is more convenient
+    /// when selecting again after losing a race. `None` waits forever.
+    #[allow(dead_code)]
+    pub fn ready_until(&self, deadline: Option<Instant>) -> Option<usize> {
+        let signal = Arc::new(Signal::new());
+        loop {
+            if let Some(i) = self.try_ready() {
+                return Some(i);
+            }
+            signal.notified.store(0, Relaxed);
+            for op in &self.operations {
+                op.waiters().register(&signal);
+            }
+            // Pairs with the fence in `Waiters::notify_one()`: either they see
+            // our signal, or we see the change they made before notifying.
+            fence(SeqCst);
+            let ready = self.try_ready();
+            let in_time = ready.is_some() || signal.wait_until(deadline);
+            for op in &self.operations {
+                op.waiters().unregister(&signal);
+            }
+            if ready.is_some() {
+                return ready;
+            }
+            if !in_time {
+                return self.try_ready();
+            }
+        }
+    }
+}
+
+/// Waits on several channel operations at once, and runs the arm of the one
+/// that completes first.
+///
+/// ```ignore
+/// select! {
+///     recv(jobs) -> job => handle(job),
+///     recv(shutdown) -> _ => return,
+///     send(results, 123) -> result => result.unwrap(),
+///     default(Duration::from_secs(1)) => println!("idle"),
+/// }
+/// ```
+///
+/// A `recv` arm gets a `Result<T, RecvError>`, and a `send` arm a `Result<(), SendError<T>>`,
+/// which is an error if the other side of that channel is gone.
+/// The value of a `send` arm is only sent if that arm is chosen.
+/// Each channel expression is evaluated once, and borrowed mutably, so it
+/// has to be a `mut` binding, or a reference like `&receiver` for channels
+/// that don't need `&mut self` to receive or send.
+/// A `default` arm runs if no operation is ready right away,
+/// and a `default(timeout)` arm runs if none becomes ready in time.
+/// Broadcast and watch receivers only work with `Select`, as `broadcast` has its
+/// own errors, and `watch` has nothing to receive.
+#[allow(unused_macros)]
+macro_rules! select {
+    ($($tokens:tt)*) => {
+        $crate::chapter_5::select::__select!(@parse [] [] $($tokens)*)
+    };
+}
+
+/// The implementation of `select!`.
+///
+/// First parses the arms one by one, declaring a variable for the result of
+/// every arm (and the value of every `send` arm) along the way. Since each
+/// of those comes from a different expansion, they don't clash, even though
+/// they're all called `__res`.
+#[doc(hidden)]
+macro_rules! __select {
+    // Split off the next arm, whether its body is a block or an expression.
+    (@parse $arms:tt $default:tt
+        $kind:ident $(($($args:tt)*))? $(-> $p:pat)? => $b:block, $($rest:tt)*) => {
+        $crate::chapter_5::select::__select!(@arm $arms $default
+            $kind [$($($args)*)?] [$($p)?] ($b) $($rest)*)
+    };
+    (@parse $arms:tt $default:tt
+        $kind:ident $(($($args:tt)*))? $(-> $p:pat)? => $b:block $($rest:tt)*) => {
+        $crate::chapter_5::select::__select!(@arm $arms $default
+            $kind [$($($args)*)?] [$($p)?] ($b) $($rest)*)
+    };
+    (@parse $arms:tt $default:tt
+        $kind:ident $(($($args:tt)*))? $(-> $p:pat)? => $b:expr $(, $($rest:tt)*)?) => {
+        $crate::chapter_5::select::__select!(@arm $arms $default
+            $kind [$($($args)*)?] [$($p)?] ($b) $($($rest)*)?)
+    };
+    (@arm [$($arms:tt)*] $default:tt recv [$r:expr] [$p:pat] ($b:expr) $($rest:tt)*) => {{
+        let __handle = &mut $r;
+        let mut __res = None;
+        $crate::chapter_5::select::__select!(@parse
+            [$($arms)* (recv __res __res __handle ($p) ($b))] $default $($rest)*)
+    }};
+    (@arm [$($arms:tt)*] $default:tt send [$s:expr, $v:expr] [$p:pat] ($b:expr) $($rest:tt)*) => {{
+        let __handle = &mut $s;
+        let mut __res = None;
+        let mut __value = Some($v);
+        $crate::chapter_5::select::__select!(@parse
+            [$($arms)* (send __res __value __handle ($p) ($b))] $default $($rest)*)
+    }};
+    (@arm $arms:tt [] default [] [] ($b:expr) $($rest:tt)*) => {
+        $crate::chapter_5::select::__select!(@parse $arms [() ($b)] $($rest)*)
+    };
+    (@arm $arms:tt [] default [$t:expr] [] ($b:expr) $($rest:tt)*) => {
+        $crate::chapter_5::select::__select!(@parse $arms [($t) ($b)] $($rest)*)
+    };
+    (@parse [$(($kind:ident $res:ident $value:ident $h:ident ($p:pat) ($b:expr)))*]
+        [$($default:tt)*]) => {{
+        // Computed once, so losing a race doesn't restart the timeout.
+        let __deadline = $crate::chapter_5::select::__select!(@deadline [$($default)*]);
+        // Select until one of the operations actually completes,
+        // and only then run its arm, so a `break` in it doesn't end up in our loop.
+        let __completed = loop {
+            let __index = {
+                let mut __sel = $crate::chapter_5::select::Select::new();
+                $( $crate::chapter_5::select::__select!(@add __sel $kind $h); )*
+                $crate::chapter_5::select::__select!(@ready __sel __deadline [$($default)*])
+            };
+            let Some(__index) = __index else { break false };
+            let mut __i = 0usize;
+            $(
+                if __index == __i
+                    && $crate::chapter_5::select::__select!(@try $kind $res $value $h)
+                {
+                    break true;
+                }
+                __i += 1;
+            )*
+            let _ = __i;
+        };
+        $( if let Some(__r) = $res.take() { let $p = __r; $b } else )* {
+            let _ = __completed;
+            $crate::chapter_5::select::__select!(@default [$($default)*])
+        }
+    }};
+    (@add $sel:ident recv $h:ident) => { $sel.recv(&*$h) };
+    (@add $sel:ident send $h:ident) => { $sel.send(&*$h) };
+    (@deadline [($t:expr) ($b:expr)]) => {
+        // A timeout too long to represent, like `Duration::MAX`, never expires.
+        ::std::time::Instant::now().checked_add($t)
+    };
+    (@deadline [$($default:tt)*]) => { None::<::std::time::Instant> };
+    (@ready $sel:ident $deadline:ident []) => { Some($sel.ready()) };
+    (@ready $sel:ident $deadline:ident [() ($b:expr)]) => { $sel.try_ready() };
+    (@ready $sel:ident $deadline:ident [($t:expr) ($b:expr)]) => { $sel.ready_until($deadline) };
+    (@try recv $res:ident $value:ident $h:ident) => {
+        match $h.try_recv() {
+            Ok(m) => {
+                $res = Some(Ok(m));
+                true
+            }
+            Err($crate::chapter_5::errors::TryRecvError::Disconnected) => {
+                $res = Some(Err($crate::chapter_5::errors::RecvError::Disconnected));
+                true
+            }
+            Err($crate::chapter_5::errors::TryRecvError::Empty) => false,
+        }
+    };
+    (@try send $res:ident $value:ident $h:ident) => {
+        match $h.try_send($value.take().unwrap()) {
+            Ok(()) => {
+                $res = Some(Ok(()));
+                true
+            }
+            Err($crate::chapter_5::errors::TrySendError::Disconnected(v)) => {
+                $res = Some(Err($crate::chapter_5::errors::SendError(v)));
+                true
+            }
+            Err($crate::chapter_5::errors::TrySendError::Full(v)) => {
+                $value = Some(v);
+                false
+            }
+        }
+    };
+    (@default []) => { unreachable!() };
+    (@default [$t:tt ($b:expr)]) => { $b };
+}
+
+#[allow(unused_imports)]
+pub(crate) use {__select, select};
+
+#[cfg(test)]
+mod tests {
+    use super::super::errors::{RecvError, SendError};
+    use super::super::{broadcast, mpmc, mpsc, rendezvous, spsc, watch};
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_select() {
+        let (job_sender, jobs) = mpmc::channel(4);
+        let (shutdown, mut shutdown_signal) = mpsc::channel::<()>();
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..10 {
+                    job_sender.send(i).unwrap();
+                    thread::sleep(Duration::from_millis(1));
+                }
+                thread::sleep(Duration::from_millis(50));
+                shutdown.send(()).unwrap();
+                // Keep the channel open, so `jobs` doesn't become ready by disconnecting.
+                thread::sleep(Duration::from_millis(50));
+            });
+
+            let mut done = Vec::new();
+            loop {
+                select! {
+                    recv(&jobs) -> job => done.push(job.unwrap()),
+                    recv(shutdown_signal) -> _ => break,
+                }
+            }
+            assert_eq!(done, (0..10).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn test_select_send() {
+        let (sender1, receiver1) = mpmc::channel(2);
+        let (sender2, receiver2) = mpmc::channel(2);
+        sender1.send(0).unwrap();
+        sender1.send(0).unwrap();
+
+        // Only the second channel has room.
+        let sent_to = select! {
+            send(&sender1, 1) -> r => { r.unwrap(); 1 },
+            send(&sender2, 2) -> r => { r.unwrap(); 2 },
+        };
+        assert_eq!(sent_to, 2);
+        assert_eq!(receiver2.try_recv(), Ok(2));
+        assert_eq!(receiver1.try_recv(), Ok(0));
+        assert_eq!(receiver1.try_recv(), Ok(0));
+
+        let (sender, receiver) = mpmc::channel(2);
+        drop(receiver);
+        let result = select! {
+            send(&sender, String::from("hi")) -> r => r,
+        };
+        assert_eq!(result, Err(SendError(String::from("hi"))));
+    }
+
+    #[test]
+    fn test_default() {
+        let (sender, receiver) = mpmc::channel::<i32>(2);
+
+        let r = select! {
+            recv(&receiver) -> m => m.ok(),
+            default => None,
+        };
+        assert_eq!(r, None);
+
+        let start = Instant::now();
+        let r = select! {
+            recv(&receiver) -> m => m.ok(),
+            default(Duration::from_millis(50)) => None,
+        };
+        assert_eq!(r, None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender.send(123).unwrap();
+            });
+            let r = select! {
+                recv(&receiver) -> m => m.ok(),
+                default(Duration::from_secs(10)) => None,
+            };
+            assert_eq!(r, Some(123));
+        });
+
+        // The channel expression is only evaluated once, and a
+        // timeout too long to represent doesn't overflow.
+        sender.send(1).unwrap();
+        let mut evaluated = 0;
+        let r = select! {
+            recv({ evaluated += 1; &receiver }) -> m => m.ok(),
+            default(Duration::MAX) => None,
+        };
+        assert_eq!((r, evaluated), (Some(1), 1));
+
+        drop(sender);
+        let r = select! {
+            recv(&receiver) -> m => m,
+            default => Ok(0),
+        };
+        assert_eq!(r, Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_select_builder() {
+        let (sender1, receiver1) = mpmc::channel::<i32>(2);
+        let (sender2, mut receiver2) = mpsc::channel::<i32>();
+
+        let mut sel = Select::new();
+        sel.recv(&receiver1);
+        let i2 = sel.recv(&receiver2);
+        assert_eq!(sel.try_ready(), None);
+        assert_eq!(sel.ready_timeout(Duration::from_millis(10)), None);
+        sender1.send(1).unwrap();
+        assert_eq!(sel.ready_timeout(Duration::MAX), Some(0));
+        assert_eq!(receiver1.try_recv(), Ok(1));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sender2.send(1).unwrap();
+            });
+            assert_eq!(sel.ready(), i2);
+        });
+        drop(sel);
+        assert_eq!(receiver2.try_recv(), Ok(1));
+
+        let mut sel = Select::new();
+        let i1 = sel.recv(&receiver1);
+        sel.recv(&receiver2);
+        sender1.send(1).unwrap();
+        assert_eq!(sel.ready(), i1);
+    }
+
+    #[test]
+    fn test_other_flavors() {
+        let (mut sender, receiver) = spsc::channel(1);
+        sender.send(0).unwrap();
+        let (rendezvous_sender, rendezvous_receiver) = rendezvous::channel();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                rendezvous_sender.send(1).unwrap();
+            });
+            // The spsc channel stays full, so it has to be the rendezvous one.
+            let r = select! {
+                send(sender, 1) -> _ => 0,
+                recv(&rendezvous_receiver) -> m => m.unwrap(),
+            };
+            assert_eq!(r, 1);
+        });
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut receiver = receiver;
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!(receiver.recv(), Ok(0));
+                assert_eq!(receiver.recv(), Ok(2));
+            });
+            let r = select! {
+                send(sender, 2) -> r => r,
+            };
+            assert_eq!(r, Ok(()));
+        });
+
+        // These don't have a `try_recv()` that fits `select!`.
+        let (mut broadcast_sender, mut broadcast_receiver) = broadcast::channel(4);
+        let (watch_sender, mut watch_receiver) = watch::channel(0);
+        let mut sel = Select::new();
+        sel.recv(&broadcast_receiver);
+        let w = sel.recv(&watch_receiver);
+        assert_eq!(sel.try_ready(), None);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                watch_sender.send(1).unwrap();
+            });
+            assert_eq!(sel.ready(), w);
+        });
+        drop(sel);
+        watch_receiver.changed().unwrap();
+
+        let mut sel = Select::new();
+        sel.recv(&watch_receiver);
+        let b = sel.recv(&broadcast_receiver);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                broadcast_sender.send(2).unwrap();
+            });
+            assert_eq!(sel.ready(), b);
+        });
+        drop(sel);
+        assert_eq!(broadcast_receiver.try_recv(), Ok(2));
+    }
+
+    #[test]
+    fn test_many_selectors() {
+        const N: usize = 10_000;
+        let (sender1, receiver1) = mpmc::channel(4);
+        let (sender2, receiver2) = mpmc::channel(4);
+
+        let total: usize = thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..N {
+                    if i % 2 == 0 {
+                        sender1.send(1).unwrap();
+                    } else {
+                        sender2.send(1).unwrap();
+                    }
+                }
+            });
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let receiver1 = receiver1.clone();
+                    let receiver2 = receiver2.clone();
+                    s.spawn(move || {
+                        let mut received = 0;
+                        let mut open = 2;
+                        while open > 0 {
+                            select! {
+                                recv(&receiver1) -> m => match m {
+                                    Ok(n) => received += n,
+                                    Err(_) => open -= 1,
+                                },
+                                recv(&receiver2) -> m => match m {
+                                    Ok(n) => received += n,
+                                    Err(_) => open -= 1,
+                                },
+                            }
+                            if open == 1 {
+                                // Don't count the same disconnected channel twice.
+                                while let Ok(n) = receiver1.recv() {
+                                    received += n;
+                                }
+                                while let Ok(n) = receiver2.recv() {
+                                    received += n;
+                                }
+                                break;
+                            }
+                        }
+                        received
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(total, N);
+    }
+}
@@ -0,0 +1,532 @@
+use super::errors::{RecvError, SendError, TryRecvError, TrySendError};
+use crate::chapter_10::seqlock::NoUninit;
+use crate::chapter_7::cache_padded::CachePadded;
+use crate::chapter_8::futex::{wait_shared, wake_one_shared};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// The start of the mapping, followed by the ring buffer.
+///
+/// This works like the `Channel` in `spsc.rs`, except that everything lives
+/// in the shared mapping, rather than behind an `Arc`, and that the futexes
+/// are shared, so the other side can be in another process.
+#[repr(C)]
+struct Header {
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    recv_waiting: AtomicU32,
+    send_waiting: AtomicU32,
+    /// Nonzero once either side is dropped.
+    disconnected: AtomicU32,
+    /// Written once when creating the mapping, so `from_fd` can check the size.
+    capacity: usize,
+    /// Shared by both processes, like everything else here. Zeroed counters are
+    /// valid, and since they change the size of the mapping, `from_fd` refuses
+    /// a mapping made by a build that disagrees on the `channel_metrics` feature.
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+/// A `memfd` mapping holding a channel for `T`s, which is not yet
+/// turned into either end.
+///
+/// The mapping can be shared with another process by forking (and turning it
+/// into the sending end in one process, and the receiving end in the other),
+/// or by passing the file descriptor and opening it with `from_fd`.
+///
+/// The messages are copied byte for byte between the processes, so `T` must be
+/// plain old data. `Copy` isn't enough for that, so it has to be `NoUninit`,
+/// and it mustn't contain pointers, which mean nothing in the other process.
+pub struct SharedChannel<T: NoUninit> {
+    fd: OwnedFd,
+    header: *mut Header,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: NoUninit + Send> Send for SharedChannel<T> {}
+
+/// Where the ring buffer starts in the mapping.
+fn buffer_offset<T>() -> usize {
+    size_of::<Header>().next_multiple_of(align_of::<T>())
+}
+
+fn mapping_len<T>(capacity: usize) -> Option<usize> {
+    capacity
+        .checked_mul(size_of::<T>())?
+        .checked_add(buffer_offset::<T>())
+}
+
+impl<T: NoUninit> SharedChannel<T> {
+    /// Creates a new mapping with room for at least `capacity` messages.
+    ///
+    /// The capacity is rounded up to a power of two.
+    #[allow(dead_code)]
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        assert!(align_of::<T>() <= align_of::<Header>());
+        let capacity = capacity.max(1).next_power_of_two();
+        let len = mapping_len::<T>(capacity).expect("capacity overflow");
+        let fd = unsafe { libc::memfd_create(c"shared_memory_spsc".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // The file starts out zeroed, which are valid (empty) indices and flags.
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let channel = Self::map(fd, len)?;
+        unsafe { (*channel.header).capacity = capacity };
+        Ok(channel)
+    }
+
+    /// Opens a mapping created by `SharedChannel::new`, for example in another process.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be a file descriptor of a `SharedChannel` for the same `T`,
+    /// of which at most one end has been or will be created elsewhere.
+    ///
+    /// If the other end is in another process, `T` must not contain any
+    /// pointers, as they'd point into our address space, not theirs.
+    #[allow(dead_code)]
+    pub unsafe fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        let mut stat: libc::stat = std::mem::zeroed();
+        if libc::fstat(fd.as_raw_fd(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = stat.st_size as usize;
+        if len < size_of::<Header>() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let channel = Self::map(fd, len)?;
+        let capacity = (*channel.header).capacity;
+        if !capacity.is_power_of_two() || mapping_len::<T>(capacity) != Some(len) {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        Ok(channel)
+    }
+
+    fn map(fd: OwnedFd, len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            header: ptr.cast(),
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    fn capacity(&self) -> usize {
+        self.header().capacity
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        unsafe {
+            self.header
+                .cast::<u8>()
+                .add(buffer_offset::<T>())
+                .cast::<T>()
+                .add(index & (self.capacity() - 1))
+        }
+    }
+
+    /// Wakes the other side if it's waiting on `waiting`.
+    ///
+    /// The SeqCst fence pairs with the one in `park()`: either we see their
+    /// `waiting` flag, or they see the index (or disconnection) we just stored.
+    fn unpark(waiting: &AtomicU32) {
+        fence(SeqCst);
+        if waiting.load(Relaxed) == 1 {
+            waiting.store(0, Relaxed);
+            wake_one_shared(waiting);
+        }
+    }
+
+    /// Waits on `waiting`, unless `ready()` turns true after announcing that we're waiting.
+    fn park(&self, waiting: &AtomicU32, ready: impl Fn() -> bool) {
+        waiting.store(1, Relaxed);
+        fence(SeqCst);
+        if ready() || self.header().disconnected.load(Relaxed) != 0 {
+            waiting.store(0, Relaxed);
+        } else {
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            wait_shared(waiting, 1);
+            #[cfg(feature = "channel_metrics")]
+            if ptr::eq(waiting, &self.header().recv_waiting) {
+                self.header().counters.recv_blocked(start.elapsed());
+            } else {
+                self.header().counters.send_blocked(start.elapsed());
+            }
+        }
+    }
+
+    /// The number of messages in the buffer, which might be outdated by the time it returns.
+    fn len(&self) -> usize {
+        let h = self.header();
+        // Loading `head` first, so it can't be ahead of `tail`.
+        let head = h.head.load(Relaxed);
+        let tail = h.tail.load(Relaxed);
+        tail.wrapping_sub(head).min(self.capacity())
+    }
+
+    /// 1 while the other end, which might be in another process, is still there.
+    fn other_count(&self) -> usize {
+        if self.header().disconnected.load(Relaxed) != 0 {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn disconnect(&self) {
+        let h = self.header();
+        // Release, so a receiver that sees this also sees everything sent before.
+        h.disconnected.store(1, Release);
+        Self::unpark(&h.recv_waiting);
+        Self::unpark(&h.send_waiting);
+    }
+
+    /// For passing the mapping to another process, which can open it with `from_fd`.
+    #[allow(dead_code)]
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+
+    #[allow(dead_code)]
+    pub fn into_sender(self) -> Sender<T> {
+        let tail = self.header().tail.load(Relaxed);
+        Sender {
+            tail,
+            cached_head: self.header().head.load(Acquire),
+            channel: self,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn into_receiver(self) -> Receiver<T> {
+        let head = self.header().head.load(Relaxed);
+        Receiver {
+            head,
+            cached_tail: self.header().tail.load(Acquire),
+            channel: self,
+        }
+    }
+}
+
+impl<T: NoUninit> Drop for SharedChannel<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.header.cast(), self.len) };
+    }
+}
+
+pub struct Sender<T: NoUninit> {
+    channel: SharedChannel<T>,
+    /// Our own copy of `tail`, which only we modify.
+    tail: usize,
+    /// The last `head` we've seen. The real one might be further along.
+    cached_head: usize,
+}
+
+impl<T: NoUninit> Sender<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.capacity())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.other_count()
+    }
+
+    /// Counts both processes, as the counters live in the shared mapping.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.header().counters.metrics()
+    }
+
+    fn is_full(&mut self) -> bool {
+        let capacity = self.channel.capacity();
+        if self.tail.wrapping_sub(self.cached_head) < capacity {
+            return false;
+        }
+        // Acquire, so the receiver is done reading the slots it gave back.
+        self.cached_head = self.channel.header().head.load(Acquire);
+        self.tail.wrapping_sub(self.cached_head) == capacity
+    }
+
+    #[allow(dead_code)]
+    pub fn try_send(&mut self, message: T) -> Result<(), TrySendError<T>> {
+        let h = self.channel.header();
+        if h.disconnected.load(Relaxed) != 0 {
+            return Err(TrySendError::Disconnected(message));
+        }
+        if self.is_full() {
+            return Err(TrySendError::Full(message));
+        }
+        unsafe { self.channel.slot(self.tail).write(message) };
+        self.tail = self.tail.wrapping_add(1);
+        let h = self.channel.header();
+        h.tail.store(self.tail, Release);
+        #[cfg(feature = "channel_metrics")]
+        h.counters.sent(1, self.channel.len());
+        SharedChannel::<T>::unpark(&h.recv_waiting);
+        Ok(())
+    }
+
+    /// Blocks while the channel is full. Fails if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(&mut self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            let c = &self.channel;
+            let h = c.header();
+            let tail = self.tail;
+            c.park(&h.send_waiting, || {
+                tail.wrapping_sub(h.head.load(Relaxed)) < c.capacity()
+            });
+        }
+    }
+}
+
+impl<T: NoUninit> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+pub struct Receiver<T: NoUninit> {
+    channel: SharedChannel<T>,
+    /// Our own copy of `head`, which only we modify.
+    head: usize,
+    /// The last `tail` we've seen. The real one might be further along.
+    cached_tail: usize,
+}
+
+impl<T: NoUninit> Receiver<T> {
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.capacity())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        self.channel.other_count()
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    /// Counts both processes, as the counters live in the shared mapping.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.header().counters.metrics()
+    }
+
+    fn caught_up(&mut self) -> bool {
+        if self.cached_tail != self.head {
+            return false;
+        }
+        // Acquire, so we see the messages the sender wrote.
+        self.cached_tail = self.channel.header().tail.load(Acquire);
+        self.cached_tail == self.head
+    }
+
+    /// Fails with `Disconnected` only once the `Sender` is gone
+    /// and all messages it sent have been received.
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if self.caught_up() {
+            if self.channel.header().disconnected.load(Acquire) == 0 {
+                return Err(TryRecvError::Empty);
+            }
+            // The sender might have sent something right before disconnecting.
+            if self.caught_up() {
+                return Err(TryRecvError::Disconnected);
+            }
+        }
+        let message = unsafe { self.channel.slot(self.head).read() };
+        self.head = self.head.wrapping_add(1);
+        let h = self.channel.header();
+        h.head.store(self.head, Release);
+        #[cfg(feature = "channel_metrics")]
+        h.counters.received(1);
+        SharedChannel::<T>::unpark(&h.send_waiting);
+        Ok(message)
+    }
+
+    /// Blocks while the channel is empty.
+    #[allow(dead_code)]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let c = &self.channel;
+            let h = c.header();
+            let head = self.head;
+            c.park(&h.recv_waiting, || h.tail.load(Relaxed) != head);
+        }
+    }
+}
+
+impl<T: NoUninit> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    struct Frame {
+        number: u64,
+        checksum: u64,
+    }
+
+    // Two `u64`s, so no padding.
+    unsafe impl NoUninit for Frame {}
+
+    impl Frame {
+        fn new(number: u64) -> Self {
+            Self {
+                number,
+                checksum: number.wrapping_mul(0x9e37_79b9_7f4a_7c15),
+            }
+        }
+    }
+
+    #[test]
+    fn test_channel() {
+        let channel = SharedChannel::new(4).unwrap();
+        // Open the same memfd a second time, as another process would.
+        let fd = channel.fd().try_clone_to_owned().unwrap();
+        let mut receiver = unsafe { SharedChannel::<Frame>::from_fd(fd) }
+            .unwrap()
+            .into_receiver();
+        let mut sender = channel.into_sender();
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(sender.capacity(), Some(4));
+        sender.send(Frame::new(0)).unwrap();
+        assert_eq!(receiver.len(), 1);
+        assert_eq!(receiver.recv(), Ok(Frame::new(0)));
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 1));
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    sender.send(Frame::new(i)).unwrap();
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(receiver.recv(), Ok(Frame::new(i)));
+            }
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+
+    #[test]
+    fn test_from_fd_checks_size() {
+        let channel = SharedChannel::<u8>::new(4).unwrap();
+        let fd = channel.fd().try_clone_to_owned().unwrap();
+        assert!(unsafe { SharedChannel::<u64>::from_fd(fd) }.is_err());
+    }
+
+    #[test]
+    fn test_fork() {
+        const N: u64 = 1_000_000;
+        let channel = SharedChannel::<Frame>::new(1024).unwrap();
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // The child only has this thread, so it should stick to things
+            // that don't allocate or take locks, and not return into the test harness.
+            let mut receiver = channel.into_receiver();
+            let mut ok = true;
+            for i in 0..N {
+                ok &= receiver.recv() == Ok(Frame::new(i));
+            }
+            ok &= receiver.recv() == Err(RecvError::Disconnected);
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+        }
+
+        let mut sender = channel.into_sender();
+        for i in 0..N {
+            sender.send(Frame::new(i)).unwrap();
+        }
+        drop(sender);
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+    }
+}
@@ -1,5 +1,175 @@
+use atomic_wait::{wait, wake_one};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicU32};
+use std::sync::Mutex;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// Like the `Channel` below, but with `N` lanes, where `receive` takes from
+/// the most urgent (lowest numbered) lane that isn't empty.
+///
+/// Instead of a `Condvar`, receivers sleep on `sent`, a futex counter that is
+/// bumped by every `send`. Since they read it before looking at the queues,
+/// a message sent in between changes the counter, and the wait returns right away.
+/// `sleepers` counts the receivers that are (about to start) waiting, so a
+/// `send` only makes a syscall if there's someone to wake up.
+///
+/// There are no separate sender and receiver handles: anyone with a reference
+/// can do either, so unlike the other channels, it has no `sender_count()`
+/// or `receiver_count()`.
+pub struct PriorityChannel<T, const N: usize> {
+    lanes: Mutex<Lanes<T, N>>,
+    sent: AtomicU32,
+    sleepers: AtomicU32,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+struct Lanes<T, const N: usize> {
+    queues: [VecDeque<T>; N],
+    /// How many times each lane had a message, but another lane was served instead.
+    skipped: [u32; N],
+    /// A lane skipped this many times gets served next, regardless of its priority.
+    max_skips: Option<u32>,
+}
+
+impl<T, const N: usize> Lanes<T, N> {
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let urgent = self.queues.iter().position(|q| !q.is_empty())?;
+        let lane = match self.max_skips {
+            Some(max) => (urgent..N)
+                .find(|&i| !self.queues[i].is_empty() && self.skipped[i] >= max)
+                .unwrap_or(urgent),
+            None => urgent,
+        };
+        for i in 0..N {
+            if i != lane && !self.queues[i].is_empty() {
+                self.skipped[i] = self.skipped[i].saturating_add(1);
+            }
+        }
+        self.skipped[lane] = 0;
+        self.queues[lane].pop_front()
+    }
+}
+
+impl<T, const N: usize> PriorityChannel<T, N> {
+    /// Strict priorities: a lane is only served once all more urgent lanes are empty.
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::with_max_skips(None)
+    }
+
+    /// Ages waiting lanes, so a lane that has been passed over `max_skips`
+    /// times while it had messages gets served next, even if it isn't the
+    /// most urgent one. This way a flood of urgent messages can't starve the others.
+    #[allow(dead_code)]
+    pub fn with_aging(max_skips: u32) -> Self {
+        Self::with_max_skips(Some(max_skips))
+    }
+
+    fn with_max_skips(max_skips: Option<u32>) -> Self {
+        assert!(N > 0, "a priority channel needs at least one lane");
+        Self {
+            lanes: Mutex::new(Lanes {
+                queues: std::array::from_fn(|_| VecDeque::new()),
+                skipped: [0; N],
+                max_skips,
+            }),
+            sent: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+            #[cfg(feature = "channel_metrics")]
+            counters: Counters::default(),
+        }
+    }
+
+    /// Sends on lane `priority`, where 0 is the most urgent one.
+    #[allow(dead_code)]
+    pub fn send(&self, priority: usize, message: T) {
+        assert!(priority < N, "priority out of range");
+        let mut lanes = self.lanes.lock().unwrap();
+        lanes.queues[priority].push_back(message);
+        #[cfg(feature = "channel_metrics")]
+        self.counters.sent(1, lanes.len());
+        drop(lanes);
+        self.sent.fetch_add(1, Release);
+        // Pairs with the fence in `receive()`: either we see them in `sleepers`,
+        // or their futex wait sees the new `sent`.
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            wake_one(&self.sent);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_receive(&self) -> Option<T> {
+        let message = self.lanes.lock().unwrap().pop();
+        #[cfg(feature = "channel_metrics")]
+        if message.is_some() {
+            self.counters.received(1);
+        }
+        message
+    }
+
+    #[allow(dead_code)]
+    pub fn receive(&self) -> T {
+        loop {
+            let sent = self.sent.load(Acquire);
+            if let Some(message) = self.try_receive() {
+                return message;
+            }
+            self.sleepers.fetch_add(1, Relaxed);
+            fence(SeqCst);
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            wait(&self.sent, sent);
+            #[cfg(feature = "channel_metrics")]
+            self.counters.recv_blocked(start.elapsed());
+            self.sleepers.fetch_sub(1, Relaxed);
+        }
+    }
+
+    /// The number of messages in all lanes together.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.lanes.lock().unwrap().len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of messages in lane `priority`.
+    #[allow(dead_code)]
+    pub fn lane_len(&self, priority: usize) -> usize {
+        self.lanes.lock().unwrap().queues[priority].len()
+    }
+
+    /// Always `None`, as the lanes are unbounded.
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        None
+    }
+
+    /// Senders never block, so `send_blocked` stays zero.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.counters.metrics()
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::PriorityChannel;
     use std::collections::VecDeque;
     use std::sync::Condvar;
     use std::sync::Mutex;
@@ -50,4 +220,69 @@ mod test {
             });
         });
     }
+
+    #[test]
+    fn testing_priority_channel() {
+        let channel: PriorityChannel<&str, 3> = PriorityChannel::new();
+
+        channel.send(2, "bulk");
+        channel.send(1, "normal");
+        channel.send(0, "urgent 1");
+        channel.send(0, "urgent 2");
+
+        assert_eq!(channel.len(), 4);
+        assert_eq!(channel.lane_len(0), 2);
+        assert_eq!(channel.capacity(), None);
+        assert_eq!(channel.receive(), "urgent 1");
+        assert_eq!(channel.receive(), "urgent 2");
+        assert_eq!(channel.receive(), "normal");
+        assert_eq!(channel.receive(), "bulk");
+        assert_eq!(channel.try_receive(), None);
+    }
+
+    #[test]
+    fn testing_priority_channel_aging() {
+        let channel: PriorityChannel<i32, 2> = PriorityChannel::with_aging(2);
+
+        for i in 0..6 {
+            channel.send(0, i);
+        }
+        channel.send(1, 100);
+
+        // The low priority lane gets its turn after being skipped twice.
+        let received: Vec<i32> = (0..7).map(|_| channel.receive()).collect();
+        assert_eq!(received, [0, 1, 100, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn testing_priority_channel_blocking() {
+        let channel: PriorityChannel<usize, 2> = PriorityChannel::new();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    channel.send(i % 2, i);
+                }
+            });
+            let mut received: Vec<usize> = (0..100).map(|_| channel.receive()).collect();
+            received.sort();
+            assert_eq!(received, (0..100).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn testing_priority_channel_sleeping_receivers() {
+        let channel: PriorityChannel<usize, 2> = PriorityChannel::new();
+
+        let received: usize = thread::scope(|s| {
+            let handles: Vec<_> = (0..3).map(|_| s.spawn(|| channel.receive())).collect();
+            // Let them all go to sleep, so every send has someone to wake up.
+            thread::sleep(std::time::Duration::from_millis(50));
+            for i in 1..=3 {
+                channel.send(i % 2, i);
+            }
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(received, 6);
+    }
 }
@@ -0,0 +1,706 @@
+use super::errors::{RecvError, SendError, TryRecvError, TrySendError};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::{SelectRecv, SelectSend};
+use super::waiters::Waiters;
+use crate::chapter_7::cache_padded::CachePadded;
+use atomic_wait::{wait, wake_one};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::Arc;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// A bounded channel for exactly one sender and one receiver, on a ring buffer.
+///
+/// `head` is only written by the receiver and `tail` only by the sender,
+/// each on its own cache line. Both sides also keep a cached copy of the
+/// other side's index, and only reload it once the cached one makes the
+/// buffer look full (or empty), to keep the cache lines from bouncing around.
+///
+/// The indices never wrap around the buffer, only around `usize`, so
+/// `tail - head` is always the number of messages in the buffer.
+struct Channel<T> {
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// The index of the next message to receive.
+    head: CachePadded<AtomicUsize>,
+    /// The index of the next slot to send into.
+    tail: CachePadded<AtomicUsize>,
+    /// 1 if the receiver is (about to start) waiting for a message.
+    recv_waiting: AtomicU32,
+    /// 1 if the sender is (about to start) waiting for a free slot.
+    send_waiting: AtomicU32,
+    /// Only for a receiver in a `Select`, as `recv()` sleeps on `recv_waiting`.
+    recv_selectors: Waiters,
+    /// Only for a sender in a `Select`, as `send()` sleeps on `send_waiting`.
+    send_selectors: Waiters,
+    /// Set when either side is dropped.
+    disconnected: AtomicBool,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+unsafe impl<T> Sync for Channel<T> where T: Send {}
+
+impl<T> Channel<T> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        // The buffer length is a power of two.
+        self.buffer[index & (self.buffer.len() - 1)].get()
+    }
+
+    /// Wakes the other side if it's waiting on `waiting`, or selecting on `selectors`.
+    ///
+    /// The SeqCst fence pairs with the one in `park()` (or `Select`): either we see
+    /// their `waiting` flag, or they see the index (or disconnection) we just stored.
+    fn unpark(waiting: &AtomicU32, selectors: &Waiters) {
+        fence(SeqCst);
+        if waiting.load(Relaxed) == 1 {
+            waiting.store(0, Relaxed);
+            wake_one(waiting);
+        }
+        selectors.notify_selectors();
+    }
+
+    /// Waits on `waiting`, unless `ready()` turns true after announcing that we're waiting.
+    fn park(&self, waiting: &AtomicU32, ready: impl Fn() -> bool) {
+        waiting.store(1, Relaxed);
+        fence(SeqCst);
+        if ready() || self.disconnected.load(Relaxed) {
+            waiting.store(0, Relaxed);
+        } else {
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            wait(waiting, 1);
+            #[cfg(feature = "channel_metrics")]
+            if std::ptr::eq(waiting, &self.recv_waiting) {
+                self.counters.recv_blocked(start.elapsed());
+            } else {
+                self.counters.send_blocked(start.elapsed());
+            }
+        }
+    }
+
+    /// The number of messages in the buffer, which might be outdated by the time it returns.
+    fn len(&self) -> usize {
+        // Loading `head` first, so it can't be ahead of `tail`.
+        let head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+        tail.wrapping_sub(head).min(self.buffer.len())
+    }
+
+    fn disconnect(&self) {
+        // Release, so a receiver that sees this also sees everything sent before.
+        self.disconnected.store(true, Release);
+        Self::unpark(&self.recv_waiting, &self.recv_selectors);
+        Self::unpark(&self.send_waiting, &self.send_selectors);
+    }
+}
+
+impl<T> Drop for Channel<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+/// Creates a channel with room for at least `capacity` messages.
+///
+/// The capacity is rounded up to a power of two.
+#[allow(dead_code)]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let a = Arc::new(Channel {
+        buffer: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+        recv_waiting: AtomicU32::new(0),
+        send_waiting: AtomicU32::new(0),
+        recv_selectors: Waiters::new(),
+        send_selectors: Waiters::new(),
+        disconnected: AtomicBool::new(false),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (
+        Sender {
+            channel: a.clone(),
+            tail: 0,
+            cached_head: 0,
+        },
+        Receiver {
+            channel: a,
+            head: 0,
+            cached_tail: 0,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+    /// Our own copy of `channel.tail`, which only we modify.
+    tail: usize,
+    /// The last `channel.head` we've seen. The real one might be further along.
+    cached_head: usize,
+}
+
+impl<T> Sender<T> {
+    /// The number of slots we can certainly write to, reloading the
+    /// receiver's index only if we'd otherwise have fewer than `wanted`.
+    fn free_slots(&mut self, wanted: usize) -> usize {
+        let capacity = self.channel.buffer.len();
+        let free = capacity - self.tail.wrapping_sub(self.cached_head);
+        if free >= wanted {
+            return free;
+        }
+        // Acquire, so the receiver is done reading the slots it gave back.
+        self.cached_head = self.channel.head.load(Acquire);
+        capacity - self.tail.wrapping_sub(self.cached_head)
+    }
+
+    fn publish(&mut self, tail: usize) {
+        #[cfg(feature = "channel_metrics")]
+        let sent = tail.wrapping_sub(self.tail);
+        self.tail = tail;
+        self.channel.tail.store(tail, Release);
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.sent(sent, self.channel.len());
+        Channel::<T>::unpark(&self.channel.recv_waiting, &self.channel.recv_selectors);
+    }
+
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        // Only the receiver can have disconnected while we're still here.
+        if self.channel.disconnected.load(Relaxed) {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    #[allow(dead_code)]
+    pub fn try_send(&mut self, message: T) -> Result<(), TrySendError<T>> {
+        if self.channel.disconnected.load(Relaxed) {
+            return Err(TrySendError::Disconnected(message));
+        }
+        if self.free_slots(1) == 0 {
+            return Err(TrySendError::Full(message));
+        }
+        unsafe { (*self.channel.slot(self.tail)).write(message) };
+        self.publish(self.tail.wrapping_add(1));
+        Ok(())
+    }
+
+    /// Blocks while the channel is full. Fails if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send(&mut self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(m)) => return Err(SendError(m)),
+                Err(TrySendError::Full(m)) => message = m,
+            }
+            let c = &*self.channel;
+            let tail = self.tail;
+            c.park(&c.send_waiting, || {
+                tail.wrapping_sub(c.head.load(Relaxed)) < c.buffer.len()
+            });
+        }
+    }
+
+    /// Sends as many of `messages` as fit right now, with a single wake up
+    /// of the receiver. Returns how many were sent, which is 0 if the
+    /// `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn push_slice(&mut self, messages: &[T]) -> usize
+    where
+        T: Copy,
+    {
+        if messages.is_empty() || self.channel.disconnected.load(Relaxed) {
+            return 0;
+        }
+        let n = self.free_slots(messages.len()).min(messages.len());
+        for (i, &message) in messages[..n].iter().enumerate() {
+            unsafe { (*self.channel.slot(self.tail.wrapping_add(i))).write(message) };
+        }
+        if n > 0 {
+            self.publish(self.tail.wrapping_add(n));
+        }
+        n
+    }
+
+    /// Sends all of `messages`, blocking whenever the channel is full, but
+    /// only waking up the receiver once per batch that fits. Fails with the
+    /// first message that couldn't be sent if the `Receiver` is gone.
+    #[allow(dead_code)]
+    pub fn send_all<I: IntoIterator<Item = T>>(&mut self, messages: I) -> Result<(), SendError<T>> {
+        let mut messages = messages.into_iter().peekable();
+        loop {
+            if messages.peek().is_none() {
+                return Ok(());
+            }
+            if self.channel.disconnected.load(Relaxed) {
+                return Err(SendError(messages.next().unwrap()));
+            }
+            let mut tail = self.tail;
+            for message in messages.by_ref().take(self.free_slots(1)) {
+                unsafe { (*self.channel.slot(tail)).write(message) };
+                tail = tail.wrapping_add(1);
+            }
+            if tail != self.tail {
+                self.publish(tail);
+            }
+            if messages.peek().is_none() {
+                return Ok(());
+            }
+            let c = &*self.channel;
+            c.park(&c.send_waiting, || {
+                tail.wrapping_sub(c.head.load(Relaxed)) < c.buffer.len()
+            });
+        }
+    }
+}
+
+impl<T> SelectSend for Sender<T> {
+    fn is_ready(&self) -> bool {
+        let c = &*self.channel;
+        self.tail.wrapping_sub(c.head.load(Relaxed)) < c.buffer.len()
+            || c.disconnected.load(Relaxed)
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.send_selectors
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    /// Our own copy of `channel.head`, which only we modify.
+    head: usize,
+    /// The last `channel.tail` we've seen. The real one might be further along.
+    cached_tail: usize,
+}
+
+impl<T> Receiver<T> {
+    /// The number of messages we can certainly read, reloading the
+    /// sender's index only if we'd otherwise have fewer than `wanted`.
+    fn available(&mut self, wanted: usize) -> usize {
+        let available = self.cached_tail.wrapping_sub(self.head);
+        if available >= wanted {
+            return available;
+        }
+        // Acquire, so we see the messages the sender wrote.
+        self.cached_tail = self.channel.tail.load(Acquire);
+        self.cached_tail.wrapping_sub(self.head)
+    }
+
+    fn publish(&mut self, head: usize) {
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.received(head.wrapping_sub(self.head));
+        self.head = head;
+        self.channel.head.store(head, Release);
+        Channel::<T>::unpark(&self.channel.send_waiting, &self.channel.send_selectors);
+    }
+
+    /// The number of messages in the channel, which might be outdated by the time it returns.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(self.channel.buffer.len())
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        // Only the sender can have disconnected while we're still here.
+        if self.channel.disconnected.load(Relaxed) {
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        1
+    }
+
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Fails with `Disconnected` only once the `Sender` is gone
+    /// and all messages it sent have been received.
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        if self.available(1) == 0 {
+            if !self.channel.disconnected.load(Acquire) {
+                return Err(TryRecvError::Empty);
+            }
+            // The sender might have sent something right before disconnecting.
+            if self.available(1) == 0 {
+                return Err(TryRecvError::Disconnected);
+            }
+        }
+        let message = unsafe { (*self.channel.slot(self.head)).assume_init_read() };
+        self.publish(self.head.wrapping_add(1));
+        Ok(message)
+    }
+
+    /// Blocks while the channel is empty.
+    #[allow(dead_code)]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(TryRecvError::Disconnected) => return Err(RecvError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let c = &*self.channel;
+            let head = self.head;
+            c.park(&c.recv_waiting, || c.tail.load(Relaxed) != head);
+        }
+    }
+
+    /// Receives as many messages as are available right now, up to `buffer.len()`,
+    /// with a single wake up of the sender. Returns how many were received.
+    #[allow(dead_code)]
+    pub fn pop_slice(&mut self, buffer: &mut [T]) -> usize
+    where
+        T: Copy,
+    {
+        let n = self.available(buffer.len()).min(buffer.len());
+        for (i, message) in buffer[..n].iter_mut().enumerate() {
+            *message =
+                unsafe { (*self.channel.slot(self.head.wrapping_add(i))).assume_init_read() };
+        }
+        if n > 0 {
+            self.publish(self.head.wrapping_add(n));
+        }
+        n
+    }
+
+    /// Blocks until there's at least one message, and then appends all that
+    /// are available to `buffer`, up to `max`, with a single wake up of the
+    /// sender. Returns how many were received.
+    #[allow(dead_code)]
+    pub fn recv_many(&mut self, buffer: &mut Vec<T>, max: usize) -> Result<usize, RecvError> {
+        if max == 0 {
+            return Ok(0);
+        }
+        loop {
+            let n = self.available(max).min(max);
+            if n > 0 {
+                // Reserve first, so nothing can panic between reading and publishing.
+                buffer.reserve(n);
+                for i in 0..n {
+                    buffer.push(unsafe {
+                        (*self.channel.slot(self.head.wrapping_add(i))).assume_init_read()
+                    });
+                }
+                self.publish(self.head.wrapping_add(n));
+                return Ok(n);
+            }
+            if self.channel.disconnected.load(Acquire) && self.available(1) == 0 {
+                return Err(RecvError::Disconnected);
+            }
+            let c = &*self.channel;
+            let head = self.head;
+            c.park(&c.recv_waiting, || c.tail.load(Relaxed) != head);
+        }
+    }
+
+    /// Ends once the `Sender` is gone and all messages it sent have been received.
+    #[allow(dead_code)]
+    pub fn iter(&mut self) -> Iter<&mut Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&mut self) -> TryIter<&mut Self> {
+        TryIter::new(self)
+    }
+}
+
+impl<T> Recv for Receiver<T> {
+    type Item = T;
+
+    fn recv(&mut self) -> Result<T, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> IntoIterator for &mut Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        self.channel.tail.load(Relaxed) != self.head || self.channel.disconnected.load(Relaxed)
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.recv_selectors
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_channel() {
+        let (mut sender, mut receiver) = channel(4);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..10 {
+                    sender.send(i).unwrap();
+                }
+            });
+            for i in 0..10 {
+                assert_eq!(receiver.recv(), Ok(i));
+            }
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+
+    #[test]
+    fn test_full_and_empty() {
+        let (mut sender, mut receiver) = channel(3);
+
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        for i in 0..4 {
+            sender.try_send(i).unwrap();
+        }
+        // Rounded up to 4.
+        assert_eq!(sender.try_send(4), Err(TrySendError::Full(4)));
+        assert_eq!(receiver.try_recv(), Ok(0));
+        sender.try_send(4).unwrap();
+        for i in 1..5 {
+            assert_eq!(receiver.try_recv(), Ok(i));
+        }
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (mut sender, receiver) = channel::<i32>(3);
+
+        assert_eq!(sender.capacity(), Some(4));
+        assert!(receiver.is_empty());
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert_eq!(sender.len(), 2);
+        assert_eq!(receiver.len(), 2);
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 1));
+        drop(receiver);
+        assert_eq!(sender.receiver_count(), 0);
+    }
+
+    #[test]
+    fn test_slices() {
+        let (mut sender, mut receiver) = channel(8);
+        let mut buffer = [0; 5];
+
+        assert_eq!(sender.push_slice(&[1, 2, 3, 4, 5, 6]), 6);
+        assert_eq!(sender.push_slice(&[7, 8, 9, 10]), 2);
+        assert_eq!(receiver.pop_slice(&mut buffer), 5);
+        assert_eq!(buffer, [1, 2, 3, 4, 5]);
+        // Wraps around the end of the buffer.
+        assert_eq!(sender.push_slice(&[9, 10, 11, 12]), 4);
+        assert_eq!(receiver.pop_slice(&mut buffer), 5);
+        assert_eq!(buffer, [6, 7, 8, 9, 10]);
+        assert_eq!(receiver.pop_slice(&mut buffer), 2);
+        assert_eq!(buffer[..2], [11, 12]);
+        assert_eq!(receiver.pop_slice(&mut buffer), 0);
+    }
+
+    #[test]
+    fn test_batches_and_iterators() {
+        let (mut sender, mut receiver) = channel(4);
+
+        thread::scope(|s| {
+            s.spawn(move || sender.send_all((0..100).map(|i| i.to_string())).unwrap());
+
+            let mut buffer = Vec::new();
+            while buffer.len() < 50 {
+                let n = receiver.recv_many(&mut buffer, 3).unwrap();
+                assert!((1..=3).contains(&n));
+            }
+            let rest: Vec<String> = receiver.iter().collect();
+            buffer.extend(rest);
+            assert_eq!(buffer, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+        });
+
+        assert_eq!(receiver.try_iter().next(), None);
+        assert_eq!(
+            receiver.recv_many(&mut Vec::new(), 3),
+            Err(RecvError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (mut sender, mut receiver) = channel(4);
+        sender.send(1).unwrap();
+        drop(sender);
+        // The message sent before disconnecting can still be received.
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Disconnected));
+
+        let (mut sender, receiver) = channel(1);
+        sender.send(1).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                drop(receiver);
+            });
+            // Blocks on the full channel until the receiver is dropped.
+            assert_eq!(sender.send(2), Err(SendError(2)));
+        });
+        assert_eq!(sender.push_slice(&[3]), 0);
+    }
+
+    #[test]
+    fn test_unreceived_messages_dropped() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let (mut sender, mut receiver) = channel(4);
+        for _ in 0..3 {
+            sender.send(DetectDrop).unwrap();
+        }
+        drop(receiver.recv());
+        assert_eq!(NUM_DROPS.load(Relaxed), 1);
+        drop(sender);
+        drop(receiver);
+        assert_eq!(NUM_DROPS.load(Relaxed), 3);
+    }
+
+    #[test]
+    fn test_stress() {
+        const N: usize = 100_000;
+        let (mut sender, mut receiver) = channel(64);
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut i = 0;
+                while i < N {
+                    let batch: Vec<usize> = (i..(i + 10).min(N)).collect();
+                    match sender.push_slice(&batch) {
+                        // Full, so block instead of spinning.
+                        0 => sender.send(i).unwrap(),
+                        n => i += n - 1,
+                    }
+                    i += 1;
+                }
+            });
+            let mut expected = 0;
+            let mut buffer = [0; 16];
+            while expected < N {
+                match receiver.pop_slice(&mut buffer) {
+                    0 => {
+                        assert_eq!(receiver.recv(), Ok(expected));
+                        expected += 1;
+                    }
+                    n => {
+                        for &i in &buffer[..n] {
+                            assert_eq!(i, expected);
+                            expected += 1;
+                        }
+                    }
+                }
+            }
+            assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+        });
+    }
+}
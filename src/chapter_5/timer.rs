@@ -0,0 +1,492 @@
+use super::errors::{RecvError, RecvTimeoutError, TryRecvError};
+use super::iter::{Iter, Recv, TryIter};
+use super::select::SelectRecv;
+use super::waiters::Waiters;
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_one};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize};
+use std::sync::{Arc, Mutex, MutexGuard, Once, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+
+// Channels whose only sender is time itself.
+//
+// All timers are kept in a single min-heap, ordered by deadline, served by a
+// single timer thread. That thread sleeps on a futex until the earliest
+// deadline, and is woken up early whenever a new timer becomes the earliest.
+// A timer that fires stores its deadline in its channel and notifies the
+// receivers, just like a sender would.
+
+/// The channel of a single `after`, `at` or `tick` timer.
+struct Timer {
+    /// The deadline of the last firing that wasn't received yet.
+    fired: Mutex<Option<Instant>>,
+    /// Only for `tick`.
+    period: Option<Duration>,
+    /// Set once the message of a one-shot timer has been received.
+    done: AtomicBool,
+    receivers: AtomicUsize,
+    waiters: Waiters,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+impl Timer {
+    fn lock(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.fired.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Called by the timer thread. Returns when to fire next, if ever.
+    ///
+    /// A tick that wasn't received yet is replaced, and ticks that were
+    /// missed entirely are skipped, so a slow receiver doesn't fall behind.
+    fn fire(&self, deadline: Instant, now: Instant) -> Option<Instant> {
+        *self.lock() = Some(deadline);
+        #[cfg(feature = "channel_metrics")]
+        self.counters.sent(1, 1);
+        self.waiters.notify_all();
+        let period = self.period?;
+        let mut next = deadline.checked_add(period)?;
+        if next <= now {
+            // Skip all missed ticks at once.
+            let missed = (now - next).as_nanos() / period.as_nanos() + 1;
+            next = next.checked_add(period.checked_mul(missed.try_into().ok()?)?)?;
+        }
+        Some(next)
+    }
+}
+
+struct Entry {
+    deadline: Instant,
+    timer: Weak<Timer>,
+}
+
+// Reversed, so the `BinaryHeap` (a max-heap) gives us the earliest deadline first.
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+/// All pending timers.
+struct Timers {
+    heap: BinaryHeap<Entry>,
+    /// When the heap grows to this size, the timers that have
+    /// no receivers anymore are removed.
+    purge_at: usize,
+}
+
+const MIN_PURGE_AT: usize = 64;
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    heap: BinaryHeap::new(),
+    purge_at: MIN_PURGE_AT,
+});
+/// Bumped whenever the earliest deadline in `TIMERS` moves up.
+static CHANGED: AtomicU32 = AtomicU32::new(0);
+static START: Once = Once::new();
+
+fn lock_timers() -> MutexGuard<'static, Timers> {
+    TIMERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn schedule(deadline: Instant, timer: &Arc<Timer>) {
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("timer".into())
+            .spawn(run)
+            .expect("failed to spawn the timer thread");
+    });
+    let mut timers = lock_timers();
+    let earliest = timers.heap.peek().is_none_or(|e| deadline < e.deadline);
+    timers.heap.push(Entry {
+        deadline,
+        timer: Arc::downgrade(timer),
+    });
+    // Timers whose receivers are all gone would otherwise stay until their deadline
+    // (or forever, for a `tick`). Purging only once the heap doubled in size keeps
+    // that cheap, while the heap can't grow to more than about twice the live timers.
+    if timers.heap.len() >= timers.purge_at {
+        timers.heap.retain(|e| e.timer.strong_count() > 0);
+        timers.purge_at = (timers.heap.len() * 2).max(MIN_PURGE_AT);
+    }
+    drop(timers);
+    if earliest {
+        CHANGED.fetch_add(1, Release);
+        wake_one(&CHANGED);
+    }
+}
+
+/// The timer thread.
+fn run() {
+    loop {
+        // Loaded before looking at the heap, so we don't sleep through a change.
+        let seq = CHANGED.load(Acquire);
+        let now = Instant::now();
+        let mut timers = lock_timers();
+        let heap = &mut timers.heap;
+        while heap.peek().is_some_and(|e| e.deadline <= now) {
+            let entry = heap.pop().unwrap();
+            // If all receivers are gone, the timer is simply forgotten.
+            let Some(timer) = entry.timer.upgrade() else {
+                continue;
+            };
+            if let Some(next) = timer.fire(entry.deadline, now) {
+                heap.push(Entry {
+                    deadline: next,
+                    timer: entry.timer,
+                });
+            }
+        }
+        let next = heap.peek().map(|e| e.deadline);
+        drop(timers);
+        match next {
+            None => wait(&CHANGED, seq),
+            Some(deadline) => wait_timeout(&CHANGED, seq, deadline - now),
+        }
+    }
+}
+
+/// Never fires if `deadline` is `None`.
+fn timer(deadline: Option<Instant>, period: Option<Duration>) -> Receiver {
+    let timer = Arc::new(Timer {
+        fired: Mutex::new(None),
+        period,
+        done: AtomicBool::new(false),
+        receivers: AtomicUsize::new(1),
+        waiters: Waiters::new(),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    if let Some(deadline) = deadline {
+        schedule(deadline, &timer);
+    }
+    Receiver { timer }
+}
+
+/// A channel that receives a single message, the deadline itself, at `deadline`.
+/// After that, it's disconnected.
+#[allow(dead_code)]
+pub fn at(deadline: Instant) -> Receiver {
+    timer(Some(deadline), None)
+}
+
+/// A channel that receives a single message after `duration`.
+/// After that, it's disconnected.
+///
+/// If `duration` is too long to represent as an `Instant`,
+/// like `Duration::MAX`, it never fires.
+#[allow(dead_code)]
+pub fn after(duration: Duration) -> Receiver {
+    timer(Instant::now().checked_add(duration), None)
+}
+
+/// A channel that receives a message every `period`, forever.
+///
+/// Each message is the time the tick was due. It holds at most one tick:
+/// ticks that aren't received in time are dropped.
+///
+/// Panics if `period` is zero.
+#[allow(dead_code)]
+pub fn tick(period: Duration) -> Receiver {
+    assert!(!period.is_zero(), "tick period must be non-zero");
+    timer(Instant::now().checked_add(period), Some(period))
+}
+
+/// The receiving end of a timer. Can be cloned, in which case
+/// every tick is received by only one of the clones.
+pub struct Receiver {
+    timer: Arc<Timer>,
+}
+
+impl Receiver {
+    /// Fails with `Disconnected` once the message of an `after` or `at` timer was received.
+    #[allow(dead_code)]
+    pub fn try_recv(&self) -> Result<Instant, TryRecvError> {
+        let t = &*self.timer;
+        if let Some(deadline) = t.lock().take() {
+            if t.period.is_none() {
+                t.done.store(true, Relaxed);
+            }
+            #[cfg(feature = "channel_metrics")]
+            t.counters.received(1);
+            return Ok(deadline);
+        }
+        if t.done.load(Relaxed) {
+            return Err(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Blocks until the timer fires.
+    #[allow(dead_code)]
+    pub fn recv(&self) -> Result<Instant, RecvError> {
+        self.recv_until(None).map_err(|_| RecvError::Disconnected)
+    }
+
+    #[allow(dead_code)]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Instant, RecvTimeoutError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<Instant, RecvTimeoutError> {
+        loop {
+            match self.try_recv() {
+                Ok(instant) => return Ok(instant),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            let ready = self
+                .timer
+                .waiters
+                .wait_until(deadline, || SelectRecv::is_ready(self));
+            #[cfg(feature = "channel_metrics")]
+            self.timer.counters.recv_blocked(start.elapsed());
+            if !ready {
+                return Err(RecvTimeoutError::Timeout);
+            }
+        }
+    }
+
+    /// 1 if the timer fired and that wasn't received yet, 0 otherwise.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.timer.lock().is_some() as usize
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// The timer itself is the sender: 1 while it can still fire, 0 once
+    /// a one-shot timer has fired.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        let t = &*self.timer;
+        if t.period.is_none() && (t.done.load(Relaxed) || t.lock().is_some()) {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.timer.receivers.load(Relaxed)
+    }
+
+    /// Timers never block, so `send_blocked` stays zero.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.timer.counters.metrics()
+    }
+
+    /// For a `tick`, this never ends.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<&Self> {
+        Iter::new(self)
+    }
+
+    #[allow(dead_code)]
+    pub fn try_iter(&self) -> TryIter<&Self> {
+        TryIter::new(self)
+    }
+}
+
+impl Recv for &Receiver {
+    type Item = Instant;
+
+    fn recv(&mut self) -> Result<Instant, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<Instant, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl Recv for Receiver {
+    type Item = Instant;
+
+    fn recv(&mut self) -> Result<Instant, RecvError> {
+        Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Result<Instant, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+}
+
+impl Clone for Receiver {
+    fn clone(&self) -> Self {
+        self.timer.receivers.fetch_add(1, Relaxed);
+        Self {
+            timer: self.timer.clone(),
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.timer.receivers.fetch_sub(1, Relaxed);
+    }
+}
+
+impl IntoIterator for Receiver {
+    type Item = Instant;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl IntoIterator for &Receiver {
+    type Item = Instant;
+    type IntoIter = Iter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Iter::new(self)
+    }
+}
+
+impl SelectRecv for Receiver {
+    fn is_ready(&self) -> bool {
+        self.timer.lock().is_some() || self.timer.done.load(Relaxed)
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.timer.waiters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mpmc;
+    use super::super::select::select;
+    use super::*;
+
+    #[test]
+    fn test_after() {
+        let start = Instant::now();
+        let receiver = after(Duration::from_millis(50));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        let deadline = receiver.recv().unwrap();
+        assert!(deadline >= start + Duration::from_millis(50));
+        assert!(Instant::now() >= deadline);
+        assert_eq!(receiver.recv(), Err(RecvError::Disconnected));
+
+        // Doesn't overflow computing the deadline.
+        let receiver = after(Duration::from_millis(10));
+        assert!(receiver.recv_timeout(Duration::MAX).is_ok());
+    }
+
+    #[test]
+    fn test_order() {
+        let start = Instant::now();
+        let late = at(start + Duration::from_millis(100));
+        let early = at(start + Duration::from_millis(20));
+        assert_eq!(early.recv(), Ok(start + Duration::from_millis(20)));
+        assert!(late.is_empty());
+        assert_eq!(
+            late.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert_eq!(late.recv(), Ok(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_tick() {
+        let period = Duration::from_millis(20);
+        let start = Instant::now();
+        let ticks: Vec<Instant> = tick(period).iter().take(3).collect();
+        for (i, t) in ticks.iter().enumerate() {
+            assert!(*t >= start + period * (i as u32 + 1));
+        }
+        assert!(ticks.windows(2).all(|w| w[1] - w[0] >= period));
+    }
+
+    #[test]
+    fn test_select_timeout() {
+        let (sender, receiver) = mpmc::channel::<i32>(1);
+        let timeout = after(Duration::from_millis(30));
+        let timed_out = select! {
+            recv(&receiver) -> _ => false,
+            recv(&timeout) -> _ => true,
+        };
+        assert!(timed_out);
+        drop(sender);
+    }
+
+    #[test]
+    fn test_never() {
+        let receiver = after(Duration::MAX);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(10)),
+            Err(RecvTimeoutError::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_counts() {
+        let receiver = after(Duration::from_millis(10));
+        let clone = receiver.clone();
+
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (1, 2));
+        drop(clone);
+        assert_eq!(receiver.receiver_count(), 1);
+        receiver.recv().unwrap();
+        assert_eq!(receiver.sender_count(), 0);
+
+        // A tick never stops.
+        let ticks = tick(Duration::from_millis(1));
+        ticks.recv().unwrap();
+        assert_eq!(ticks.sender_count(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_tick() {
+        tick(Duration::ZERO);
+    }
+
+    #[test]
+    fn test_purge() {
+        // Used as select timeouts, and then dropped without ever firing.
+        for _ in 0..1000 {
+            drop(after(Duration::from_secs(3600)));
+        }
+        let _live = after(Duration::from_secs(3600));
+        assert!(lock_timers().heap.len() <= 2 * MIN_PURGE_AT);
+    }
+}
@@ -0,0 +1,112 @@
+use super::select::Signal;
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_all, wake_one};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, SeqCst};
+use std::sync::atomic::{fence, AtomicU32};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A place for any number of threads to sleep until some condition turns true,
+/// for channels where more than one thread can be waiting on the same side.
+///
+/// `futex` is bumped on every notification, and `sleepers` counts the threads
+/// that are (about to start) waiting, so notifying is only a fence and a load
+/// when nobody is waiting.
+///
+/// Threads blocked in a `Select` can't sleep on more than one futex, so they
+/// register a `Signal` here instead, which gets notified on every notification.
+/// Channels that put their own threads to sleep some other way can still keep
+/// one of these just for `Select`, and call `notify_selectors()` themselves.
+pub struct Waiters {
+    futex: AtomicU32,
+    sleepers: AtomicU32,
+    selectors: Mutex<Vec<Arc<Signal>>>,
+    num_selectors: AtomicU32,
+}
+
+impl Waiters {
+    pub const fn new() -> Self {
+        Self {
+            futex: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+            selectors: Mutex::new(Vec::new()),
+            num_selectors: AtomicU32::new(0),
+        }
+    }
+
+    /// Blocks until notified, unless `ready()` is already true once we've
+    /// announced that we're going to sleep. Returns false if `deadline` passed.
+    ///
+    /// Can return spuriously, so call it in a loop that checks the condition.
+    pub fn wait_until(&self, deadline: Option<Instant>, ready: impl Fn() -> bool) -> bool {
+        let seq = self.futex.load(Acquire);
+        self.sleepers.fetch_add(1, SeqCst);
+        // Pairs with the fence in `notify_one()`: either they see us in
+        // `sleepers`, or `ready()` sees what they did before notifying.
+        fence(SeqCst);
+        let mut in_time = true;
+        if !ready() {
+            match deadline {
+                None => wait(&self.futex, seq),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        in_time = false;
+                    } else {
+                        wait_timeout(&self.futex, seq, deadline - now);
+                    }
+                }
+            }
+        }
+        self.sleepers.fetch_sub(1, Relaxed);
+        in_time
+    }
+
+    /// Wakes one sleeping thread, if there is any.
+    pub fn notify_one(&self) {
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            self.futex.fetch_add(1, Relaxed);
+            wake_one(&self.futex);
+        }
+        self.notify_selectors();
+    }
+
+    pub fn notify_all(&self) {
+        fence(SeqCst);
+        if self.sleepers.load(Relaxed) > 0 {
+            self.futex.fetch_add(1, Relaxed);
+            wake_all(&self.futex);
+        }
+        self.notify_selectors();
+    }
+
+    /// Only call after a SeqCst fence, which pairs with the one in `Select`,
+    /// after registering and before checking if any operation is ready.
+    pub fn notify_selectors(&self) {
+        if self.num_selectors.load(Relaxed) > 0 {
+            for signal in self
+                .selectors
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+            {
+                signal.notify();
+            }
+        }
+    }
+
+    pub fn register(&self, signal: &Arc<Signal>) {
+        let mut selectors = self.selectors.lock().unwrap_or_else(|e| e.into_inner());
+        selectors.push(signal.clone());
+        self.num_selectors.fetch_add(1, Relaxed);
+    }
+
+    pub fn unregister(&self, signal: &Arc<Signal>) {
+        let mut selectors = self.selectors.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = selectors.iter().position(|s| Arc::ptr_eq(s, signal)) {
+            selectors.swap_remove(i);
+            self.num_selectors.fetch_sub(1, Relaxed);
+        }
+    }
+}
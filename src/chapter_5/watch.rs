@@ -0,0 +1,392 @@
+use super::errors::{RecvError, SendError};
+use super::select::SelectRecv;
+use super::waiters::Waiters;
+use crate::chapter_9::rwlock_no_writer_stravation::{ReadGuard, RwLock};
+use atomic_wait::{wait, wake_all};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU32, AtomicUsize};
+use std::sync::Arc;
+
+#[cfg(feature = "channel_metrics")]
+use super::metrics::{ChannelMetrics, Counters};
+#[cfg(feature = "channel_metrics")]
+use std::time::Instant;
+
+/// Set in `version` once the sender is gone.
+const CLOSED: u32 = 1;
+
+/// A channel that only holds the latest value, which receivers can look at
+/// whenever they want, or wait for it to change.
+///
+/// Every new value bumps `version` by two, and receivers remember the last
+/// version they've seen. Waiting for a change is a futex wait on `version`,
+/// so dropping the sender, which sets the lowest bit, wakes them up as well.
+struct Channel<T> {
+    value: RwLock<T>,
+    version: AtomicU32,
+    /// Only for receivers in a `Select`, which can't wait on `version`.
+    selectors: Waiters,
+    receivers: AtomicUsize,
+    #[cfg(feature = "channel_metrics")]
+    counters: Counters,
+}
+
+impl<T> Channel<T> {
+    /// Marks `version` as seen by a receiver that had seen `seen` before.
+    fn see(&self, seen: &mut u32, version: u32) {
+        #[cfg(feature = "channel_metrics")]
+        if version != *seen {
+            self.counters.received(1);
+        }
+        *seen = version;
+    }
+}
+
+#[allow(dead_code)]
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let a = Arc::new(Channel {
+        value: RwLock::new(initial),
+        version: AtomicU32::new(0),
+        selectors: Waiters::new(),
+        receivers: AtomicUsize::new(1),
+        #[cfg(feature = "channel_metrics")]
+        counters: Counters::default(),
+    });
+    (
+        Sender { channel: a.clone() },
+        Receiver {
+            channel: a,
+            seen: 0,
+        },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Always 1, as there's always exactly one value.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    /// Always 1: this one.
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        1
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    /// `received` counts the values receivers marked as seen, and senders never block.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// Replaces the value, and wakes up everyone waiting for a change.
+    /// Fails, giving the value back, if there are no receivers.
+    #[allow(dead_code)]
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        if self.channel.receivers.load(Relaxed) == 0 {
+            return Err(SendError(value));
+        }
+        self.send_replace(value);
+        Ok(())
+    }
+
+    /// Like `send()`, but also works without receivers, and returns the old value.
+    #[allow(dead_code)]
+    pub fn send_replace(&self, value: T) -> T {
+        let mut guard = self.channel.value.write();
+        let old = std::mem::replace(&mut *guard, value);
+        // Bump it before unlocking, so a reader holding the lock
+        // never pairs the new value with the old version.
+        self.channel.version.fetch_add(2, Release);
+        drop(guard);
+        #[cfg(feature = "channel_metrics")]
+        self.channel.counters.sent(1, 1);
+        wake_all(&self.channel.version);
+        self.channel.selectors.notify_all();
+        old
+    }
+
+    #[allow(dead_code)]
+    pub fn borrow(&self) -> ReadGuard<'_, T> {
+        self.channel.value.read()
+    }
+
+    /// Creates a new receiver, which considers the current value as seen.
+    #[allow(dead_code)]
+    pub fn subscribe(&self) -> Receiver<T> {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        // Under the lock, like `borrow_and_update()`.
+        let guard = self.channel.value.read();
+        let seen = self.channel.version.load(Acquire) & !CLOSED;
+        drop(guard);
+        Receiver {
+            channel: self.channel.clone(),
+            seen,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.version.fetch_or(CLOSED, Release);
+        wake_all(&self.channel.version);
+        self.channel.selectors.notify_all();
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+    /// The version of the value we've last seen.
+    seen: u32,
+}
+
+impl<T> Receiver<T> {
+    /// 1 if there's a value we haven't seen yet, 0 otherwise.
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.has_changed() as usize
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[allow(dead_code)]
+    pub fn capacity(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    #[allow(dead_code)]
+    pub fn sender_count(&self) -> usize {
+        if self.channel.version.load(Relaxed) & CLOSED != 0 {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+
+    /// `received` counts the values receivers marked as seen, and senders never block.
+    #[cfg(feature = "channel_metrics")]
+    #[allow(dead_code)]
+    pub fn metrics(&self) -> ChannelMetrics {
+        self.channel.counters.metrics()
+    }
+
+    /// The latest value, without marking it as seen.
+    ///
+    /// Keeping it borrowed blocks the sender, so don't hold on to it for long.
+    #[allow(dead_code)]
+    pub fn borrow(&self) -> ReadGuard<'_, T> {
+        self.channel.value.read()
+    }
+
+    /// The latest value, marking it as seen.
+    #[allow(dead_code)]
+    pub fn borrow_and_update(&mut self) -> ReadGuard<'_, T> {
+        // Read under the lock, so it's really the version of the value we return.
+        let guard = self.channel.value.read();
+        let version = self.channel.version.load(Acquire) & !CLOSED;
+        self.channel.see(&mut self.seen, version);
+        guard
+    }
+
+    /// Whether there's a value we haven't seen yet.
+    #[allow(dead_code)]
+    pub fn has_changed(&self) -> bool {
+        self.channel.version.load(Acquire) & !CLOSED != self.seen
+    }
+
+    /// Blocks until there's a value we haven't seen yet, and marks it as seen.
+    /// Fails if the sender is gone, and we've seen the last value already.
+    #[allow(dead_code)]
+    pub fn changed(&mut self) -> Result<(), RecvError> {
+        loop {
+            let version = self.channel.version.load(Acquire);
+            if version & !CLOSED != self.seen {
+                self.channel.see(&mut self.seen, version & !CLOSED);
+                return Ok(());
+            }
+            if version & CLOSED != 0 {
+                return Err(RecvError::Disconnected);
+            }
+            #[cfg(feature = "channel_metrics")]
+            let start = Instant::now();
+            wait(&self.channel.version, version);
+            #[cfg(feature = "channel_metrics")]
+            self.channel.counters.recv_blocked(start.elapsed());
+        }
+    }
+}
+
+/// Ready once `changed()` wouldn't block. Works with `Select`, but not with
+/// `select!`, as there's no message to receive: call `changed()` instead.
+impl<T> SelectRecv for Receiver<T> {
+    fn is_ready(&self) -> bool {
+        // `seen` never has the `CLOSED` bit, so this includes the sender being gone.
+        self.channel.version.load(Relaxed) != self.seen
+    }
+
+    fn waiters(&self) -> &Waiters {
+        &self.channel.selectors
+    }
+}
+
+/// The clone has seen the same values as the original.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Self {
+            channel: self.channel.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.fetch_sub(1, Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_watch() {
+        let (sender, mut receiver) = channel("initial");
+
+        assert_eq!(*receiver.borrow(), "initial");
+        assert!(!receiver.has_changed());
+
+        sender.send("first").unwrap();
+        sender.send("second").unwrap();
+        assert!(receiver.has_changed());
+        // Only the latest value is kept.
+        assert_eq!(*receiver.borrow(), "second");
+        assert!(receiver.has_changed());
+        assert_eq!(*receiver.borrow_and_update(), "second");
+        assert!(!receiver.has_changed());
+
+        assert_eq!(sender.send_replace("third"), "second");
+        receiver.changed().unwrap();
+        assert_eq!(*receiver.borrow(), "third");
+    }
+
+    #[test]
+    fn test_changed() {
+        let (sender, receiver) = channel(0);
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    let mut receiver = receiver.clone();
+                    s.spawn(move || {
+                        let mut seen = Vec::new();
+                        while receiver.changed().is_ok() {
+                            seen.push(*receiver.borrow());
+                        }
+                        seen
+                    })
+                })
+                .collect();
+
+            for i in 1..=10 {
+                thread::sleep(Duration::from_millis(1));
+                sender.send(i).unwrap();
+            }
+            thread::sleep(Duration::from_millis(50));
+            drop(sender);
+
+            for h in handles {
+                let seen = h.join().unwrap();
+                // Values may be skipped, but never go back, and the last one is always seen.
+                assert!(seen.windows(2).all(|w| w[0] < w[1]));
+                assert_eq!(seen.last(), Some(&10));
+            }
+        });
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let (sender, mut receiver) = channel(1);
+        let mut late = sender.subscribe();
+
+        sender.send(2).unwrap();
+        drop(sender);
+        // The last value still counts as a change.
+        assert_eq!(receiver.changed(), Ok(()));
+        assert_eq!(receiver.changed(), Err(RecvError::Disconnected));
+        assert_eq!(late.changed(), Ok(()));
+        assert_eq!(*late.borrow(), 2);
+
+        let (sender, receiver) = channel(1);
+        drop(receiver);
+        assert_eq!(sender.send(2), Err(SendError(2)));
+        assert_eq!(sender.send_replace(3), 1);
+        assert_eq!(*sender.borrow(), 3);
+    }
+
+    #[test]
+    fn test_len_and_counts() {
+        let (sender, mut receiver) = channel(0);
+        let late = sender.subscribe();
+
+        assert_eq!((sender.len(), sender.capacity()), (1, Some(1)));
+        assert!(receiver.is_empty());
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        // Only the latest value counts.
+        assert_eq!(receiver.len(), 1);
+        receiver.borrow_and_update();
+        assert!(receiver.is_empty());
+        assert_eq!(late.len(), 1);
+
+        assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 2));
+        drop(late);
+        drop(sender);
+        assert_eq!((receiver.sender_count(), receiver.receiver_count()), (0, 1));
+    }
+
+    #[test]
+    fn test_wake_on_disconnect() {
+        let (sender, mut receiver) = channel(());
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                drop(sender);
+            });
+            assert_eq!(receiver.changed(), Err(RecvError::Disconnected));
+        });
+    }
+}
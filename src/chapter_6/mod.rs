@@ -1,3 +1,4 @@
 mod basic_reference_counting;
 mod optimizing;
+mod thin_arc;
 mod weak_pointers;
@@ -1,27 +1,32 @@
 #[cfg(test)]
 mod test {
+    use std::alloc::{alloc, handle_alloc_error, Layout};
     use std::cell::UnsafeCell;
-    use std::mem::ManuallyDrop;
+    use std::fmt::Display;
+    use std::mem::{self, ManuallyDrop, MaybeUninit};
     use std::ops::Deref;
-    use std::ptr::NonNull;
+    use std::ptr::{self, NonNull};
     use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
     use std::sync::atomic::{fence, AtomicUsize};
 
-    struct Arc<T> {
+    struct Arc<T: ?Sized> {
         ptr: NonNull<ArcData<T>>,
     }
 
-    unsafe impl<T: Sync + Send> Send for Arc<T> {}
-    unsafe impl<T: Sync + Send> Sync for Arc<T> {}
+    unsafe impl<T: Sync + Send + ?Sized> Send for Arc<T> {}
+    unsafe impl<T: Sync + Send + ?Sized> Sync for Arc<T> {}
 
-    struct Weak<T> {
+    struct Weak<T: ?Sized> {
         ptr: NonNull<ArcData<T>>,
     }
 
-    unsafe impl<T: Sync + Send> Send for Weak<T> {}
-    unsafe impl<T: Sync + Send> Sync for Weak<T> {}
+    unsafe impl<T: Sync + Send + ?Sized> Send for Weak<T> {}
+    unsafe impl<T: Sync + Send + ?Sized> Sync for Weak<T> {}
 
-    struct ArcData<T> {
+    // `repr(C)`, so `ArcData<MaybeUninit<T>>` has the same layout as `ArcData<T>`,
+    // and so we can tell where the data of an unsized `ArcData` starts.
+    #[repr(C)]
+    struct ArcData<T: ?Sized> {
         /// Number of `Arc`s.
         data_ref_count: AtomicUsize,
         /// Number of `Weak`s, plus one if there are any `Arc`s
@@ -41,10 +46,156 @@ mod test {
             }
         }
 
+        /// Like `new`, but gives `f` a `Weak` to the `Arc` under construction,
+        /// which can't be upgraded until `f` returns.
+        pub fn new_cyclic(f: impl FnOnce(&Weak<T>) -> T) -> Arc<T> {
+            let ptr = NonNull::from(Box::leak(Box::new(ArcData {
+                // This one becomes the implicit weak pointer of the `Arc`s.
+                alloc_ref_count: AtomicUsize::new(1),
+                data_ref_count: AtomicUsize::new(0),
+                data: UnsafeCell::new(ManuallyDrop::new(MaybeUninit::<T>::uninit())),
+            })))
+            .cast::<ArcData<T>>();
+            // If `f` panics, this frees the allocation, without touching the data.
+            let weak = Weak { ptr };
+            let data = f(&weak);
+            unsafe { (*weak.data().data.get()) = ManuallyDrop::new(data) };
+            weak.data().data_ref_count.store(1, Release);
+            mem::forget(weak);
+            Arc { ptr }
+        }
+
+        /// Gives the data back if this is the only `Arc`.
+        pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+            if arc
+                .data()
+                .data_ref_count
+                .compare_exchange(1, 0, Acquire, Relaxed)
+                .is_err()
+            {
+                return Err(arc);
+            }
+            let arc = ManuallyDrop::new(arc);
+            // Safety: We were the last `Arc`, and `Weak`s can't upgrade anymore.
+            let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            drop(Weak { ptr: arc.ptr });
+            Ok(data)
+        }
+
+        /// Drops this `Arc`, giving the data back if it was the last one.
+        ///
+        /// Unlike `try_unwrap`, when several threads race to drop their `Arc`,
+        /// exactly one of them gets the data.
+        pub fn into_inner(arc: Self) -> Option<T> {
+            let arc = ManuallyDrop::new(arc);
+            if arc.data().data_ref_count.fetch_sub(1, Release) != 1 {
+                return None;
+            }
+            fence(Acquire);
+            let data = unsafe { ManuallyDrop::take(&mut *arc.data().data.get()) };
+            drop(Weak { ptr: arc.ptr });
+            Some(data)
+        }
+
+        /// Clones the data into a new allocation, unless this is the only `Arc`.
+        ///
+        /// If there are only `Weak`s left, the data is moved to a new
+        /// allocation instead, and those `Weak`s can no longer be upgraded.
+        pub fn make_mut(arc: &mut Self) -> &mut T
+        where
+            T: Clone,
+        {
+            let data = arc.data();
+            if data
+                .data_ref_count
+                .compare_exchange(1, 0, Acquire, Relaxed)
+                .is_err()
+            {
+                *arc = Arc::new((**arc).clone());
+            } else if data.alloc_ref_count.load(Relaxed) != 1 {
+                // Safety: We were the last `Arc`, and `Weak`s can't upgrade anymore.
+                let moved = Arc::new(unsafe { ptr::read(&**data.data.get()) });
+                let old = ManuallyDrop::new(mem::replace(arc, moved));
+                drop(Weak { ptr: old.ptr });
+            } else {
+                // There are no `Weak`s, and we're the only `Arc`, so nobody can make new ones.
+                data.data_ref_count.store(1, Release);
+            }
+            // Safety: We're now the only `Arc`, and there are no `Weak`s.
+            unsafe { &mut *arc.data().data.get() }
+        }
+
+        /// Turns this into an `Arc` of an unsized type, like `Arc<dyn Trait>`,
+        /// which `Arc` can't do by itself without the unstable `CoerceUnsized`.
+        ///
+        /// # Safety
+        ///
+        /// `f` may only unsize the pointer through an implicit coercion,
+        /// as in `Arc::coerce::<dyn Trait>(arc, |p| p)`. Any other cast,
+        /// like one to a longer slice or a bigger type, can't be detected,
+        /// and makes the `Arc` point past its allocation.
+        pub unsafe fn coerce<U: ?Sized>(
+            arc: Self,
+            f: impl FnOnce(*mut ArcData<T>) -> *mut ArcData<U>,
+        ) -> Arc<U> {
+            let arc = ManuallyDrop::new(arc);
+            let ptr = f(arc.ptr.as_ptr());
+            assert!(ptr::addr_eq(ptr, arc.ptr.as_ptr()));
+            Arc {
+                ptr: NonNull::new(ptr).unwrap(),
+            }
+        }
+    }
+
+    impl<T: ?Sized> Arc<T> {
         fn data(&self) -> &ArcData<T> {
             unsafe { self.ptr.as_ref() }
         }
 
+        /// Ignores the metadata, like the vtable of a `dyn Trait`.
+        pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+            ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+        }
+
+        pub fn strong_count(arc: &Self) -> usize {
+            arc.data().data_ref_count.load(Relaxed)
+        }
+
+        pub fn weak_count(arc: &Self) -> usize {
+            match arc.data().alloc_ref_count.load(Relaxed) {
+                // Locked by `get_mut`, which only does that if there are no `Weak`s.
+                usize::MAX => 0,
+                // Minus the implicit one of the `Arc`s.
+                n => n - 1,
+            }
+        }
+
+        pub fn as_ptr(arc: &Self) -> *const T {
+            // Not through `data()`, so the pointer is still valid for the whole allocation,
+            // which `from_raw` needs.
+            unsafe { ptr::addr_of_mut!((*arc.ptr.as_ptr()).data) as *const T }
+        }
+
+        /// Leaks the `Arc`, for `from_raw` to restore later.
+        pub fn into_raw(arc: Self) -> *const T {
+            let ptr = Self::as_ptr(&arc);
+            mem::forget(arc);
+            ptr
+        }
+
+        /// Safety: `ptr` must come from `into_raw`, and each call
+        /// to `into_raw` can be paired with only one call to this.
+        pub unsafe fn from_raw(ptr: *const T) -> Self {
+            // The counters, followed by the data, padded to its alignment.
+            let counters = Layout::new::<ArcData<()>>();
+            let data = Layout::from_size_align_unchecked(0, mem::align_of_val(&*ptr));
+            let (_, offset) = counters.extend(data).unwrap();
+            let ptr = ptr.byte_sub(offset) as *mut ArcData<T>;
+            Arc {
+                ptr: NonNull::new_unchecked(ptr),
+            }
+        }
+
         #[allow(dead_code)]
         pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
             if arc
@@ -87,7 +238,7 @@ mod test {
         }
     }
 
-    impl<T> Clone for Arc<T> {
+    impl<T: ?Sized> Clone for Arc<T> {
         fn clone(&self) -> Self {
             if self.data().data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
                 std::process::abort();
@@ -96,7 +247,7 @@ mod test {
         }
     }
 
-    impl<T> Drop for Arc<T> {
+    impl<T: ?Sized> Drop for Arc<T> {
         fn drop(&mut self) {
             if self.data().data_ref_count.fetch_sub(1, Release) == 1 {
                 fence(Acquire);
@@ -112,7 +263,7 @@ mod test {
         }
     }
 
-    impl<T> Deref for Arc<T> {
+    impl<T: ?Sized> Deref for Arc<T> {
         type Target = T;
 
         fn deref(&self) -> &T {
@@ -123,11 +274,27 @@ mod test {
     }
 
     impl<T> Weak<T> {
+        /// A `Weak` without an allocation, which never upgrades.
+        pub fn new() -> Weak<T> {
+            Weak {
+                ptr: NonNull::new(ptr::without_provenance_mut(usize::MAX)).unwrap(),
+            }
+        }
+    }
+
+    impl<T: ?Sized> Weak<T> {
+        fn is_dangling(&self) -> bool {
+            self.ptr.as_ptr().addr() == usize::MAX
+        }
+
         fn data(&self) -> &ArcData<T> {
             unsafe { self.ptr.as_ref() }
         }
 
         pub fn upgrade(&self) -> Option<Arc<T>> {
+            if self.is_dangling() {
+                return None;
+            }
             let mut n = self.data().data_ref_count.load(Relaxed);
             loop {
                 if n == 0 {
@@ -135,10 +302,11 @@ mod test {
                 }
 
                 assert!(n < usize::MAX);
-                if let Err(e) =
-                    self.data()
-                        .data_ref_count
-                        .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+                if let Err(e) = self
+                    .data()
+                    .data_ref_count
+                    // Acquire, so we see the data `new_cyclic` wrote after handing out the `Weak`.
+                    .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
                 {
                     n = e;
                     continue;
@@ -148,8 +316,11 @@ mod test {
         }
     }
 
-    impl<T> Clone for Weak<T> {
+    impl<T: ?Sized> Clone for Weak<T> {
         fn clone(&self) -> Self {
+            if self.is_dangling() {
+                return Weak { ptr: self.ptr };
+            }
             if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
                 std::process::abort();
             }
@@ -157,8 +328,11 @@ mod test {
         }
     }
 
-    impl<T> Drop for Weak<T> {
+    impl<T: ?Sized> Drop for Weak<T> {
         fn drop(&mut self) {
+            if self.is_dangling() {
+                return;
+            }
             if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
                 fence(Acquire);
                 unsafe {
@@ -168,6 +342,60 @@ mod test {
         }
     }
 
+    impl<T> Arc<[T]> {
+        /// Allocates the counters and room for `len` elements, which are left uninitialized.
+        fn allocate(len: usize) -> NonNull<ArcData<[T]>> {
+            let counters = Layout::new::<ArcData<()>>();
+            let (layout, _) = counters.extend(Layout::array::<T>(len).unwrap()).unwrap();
+            let layout = layout.pad_to_align();
+            let ptr = unsafe { alloc(layout) };
+            if ptr.is_null() {
+                handle_alloc_error(layout);
+            }
+            let ptr = ptr::slice_from_raw_parts_mut(ptr as *mut T, len) as *mut ArcData<[T]>;
+            unsafe {
+                ptr::addr_of_mut!((*ptr).data_ref_count).write(AtomicUsize::new(1));
+                ptr::addr_of_mut!((*ptr).alloc_ref_count).write(AtomicUsize::new(1));
+                NonNull::new_unchecked(ptr)
+            }
+        }
+    }
+
+    impl<T> From<Vec<T>> for Arc<[T]> {
+        fn from(mut v: Vec<T>) -> Self {
+            let ptr = Self::allocate(v.len());
+            unsafe {
+                let data = ptr::addr_of_mut!((*ptr.as_ptr()).data) as *mut T;
+                ptr::copy_nonoverlapping(v.as_ptr(), data, v.len());
+                // The elements are moved, so only free the `Vec`'s buffer.
+                v.set_len(0);
+            }
+            Arc { ptr }
+        }
+    }
+
+    impl<T> FromIterator<T> for Arc<[T]> {
+        fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+            Self::from(iter.into_iter().collect::<Vec<T>>())
+        }
+    }
+
+    impl From<String> for Arc<str> {
+        fn from(s: String) -> Self {
+            let bytes = ManuallyDrop::new(Arc::<[u8]>::from(s.into_bytes()));
+            // Safety: The bytes are valid UTF-8, and `str` has the same layout as `[u8]`.
+            Arc {
+                ptr: unsafe { NonNull::new_unchecked(bytes.ptr.as_ptr() as *mut ArcData<str>) },
+            }
+        }
+    }
+
+    impl From<&str> for Arc<str> {
+        fn from(s: &str) -> Self {
+            Self::from(s.to_string())
+        }
+    }
+
     #[test]
     fn test() {
         static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
@@ -205,4 +433,227 @@ mod test {
         assert_eq!(NUM_DROPS.load(Relaxed), 1);
         assert!(z.upgrade().is_none());
     }
+
+    #[test]
+    fn test_try_unwrap() {
+        let x = Arc::new(String::from("Hello"));
+        let y = x.clone();
+        let x = Arc::try_unwrap(x).unwrap_err();
+        drop(y);
+        let w = Arc::downgrade(&x);
+        assert_eq!(Arc::try_unwrap(x).ok().unwrap(), "Hello");
+        assert!(w.upgrade().is_none());
+    }
+
+    /// Fewer iterations under Miri, which is a lot slower.
+    const ITERATIONS: usize = if cfg!(miri) { 10 } else { 100 };
+
+    #[test]
+    fn test_into_inner() {
+        for _ in 0..ITERATIONS / 10 {
+            let x = Arc::new(String::from("Hello"));
+            let results: Vec<Option<String>> = std::thread::scope(|s| {
+                let threads: Vec<_> = (0..4)
+                    .map(|_| {
+                        let x = x.clone();
+                        s.spawn(move || Arc::into_inner(x))
+                    })
+                    .collect();
+                let mut results = vec![Arc::into_inner(x)];
+                results.extend(threads.into_iter().map(|t| t.join().unwrap()));
+                results
+            });
+            // Whoever dropped the last one got it.
+            assert_eq!(results.iter().flatten().count(), 1);
+        }
+        let x = Arc::new(1);
+        let w = Arc::downgrade(&x);
+        assert_eq!(Arc::into_inner(x), Some(1));
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_make_mut() {
+        let mut x = Arc::new(vec![1]);
+        let ptr = Arc::as_ptr(&x);
+        // Unique, so it's modified in place.
+        Arc::make_mut(&mut x).push(2);
+        assert_eq!(Arc::as_ptr(&x), ptr);
+
+        // Shared, so it's cloned first.
+        let y = x.clone();
+        Arc::make_mut(&mut x).push(3);
+        assert_eq!(*x, [1, 2, 3]);
+        assert_eq!(*y, [1, 2]);
+        assert!(!Arc::ptr_eq(&x, &y));
+
+        // Only a weak pointer left, so it's moved out from under it.
+        let w = Arc::downgrade(&y);
+        let mut y = y;
+        Arc::make_mut(&mut y).push(4);
+        assert_eq!(*y, [1, 2, 4]);
+        assert!(w.upgrade().is_none());
+        assert_eq!(Arc::weak_count(&y), 0);
+    }
+
+    #[test]
+    fn test_counts() {
+        let x = Arc::new(1);
+        let y = x.clone();
+        let w = Arc::downgrade(&x);
+        assert!(Arc::ptr_eq(&x, &y));
+        assert!(!Arc::ptr_eq(&x, &Arc::new(1)));
+        assert_eq!((Arc::strong_count(&x), Arc::weak_count(&x)), (2, 1));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        drop((x.clone(), w.clone()));
+                    }
+                });
+            }
+        });
+        drop(y);
+        drop(w);
+        assert_eq!((Arc::strong_count(&x), Arc::weak_count(&x)), (1, 0));
+    }
+
+    #[test]
+    fn test_raw() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(u8);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let x = Arc::new(DetectDrop(123));
+        let ptr = Arc::as_ptr(&x);
+        let raw = Arc::into_raw(x);
+        assert_eq!(raw, ptr);
+        assert_eq!(unsafe { (*raw).0 }, 123);
+        assert_eq!(NUM_DROPS.load(Relaxed), 0);
+        let x = unsafe { Arc::from_raw(raw) };
+        assert_eq!(x.0, 123);
+        drop(x);
+        assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn test_new_cyclic() {
+        struct Node {
+            me: Weak<Node>,
+            value: i32,
+        }
+
+        let node = Arc::new_cyclic(|me| {
+            assert!(me.upgrade().is_none());
+            Node {
+                me: me.clone(),
+                value: 1,
+            }
+        });
+        let me = node.me.upgrade().unwrap();
+        assert!(Arc::ptr_eq(&node, &me));
+        assert_eq!(me.value, 1);
+        assert_eq!((Arc::strong_count(&node), Arc::weak_count(&node)), (2, 1));
+    }
+
+    #[test]
+    fn test_new_cyclic_upgrade_elsewhere() {
+        std::thread::scope(|s| {
+            let mut thread = None;
+            let x = Arc::new_cyclic(|me: &Weak<String>| {
+                let me = me.clone();
+                thread = Some(s.spawn(move || loop {
+                    // Once it upgrades, the data must be fully there.
+                    if let Some(x) = me.upgrade() {
+                        assert_eq!(*x, "Hello");
+                        break;
+                    }
+                    std::thread::yield_now();
+                }));
+                String::from("Hello")
+            });
+            // Keep `x` alive until the thread managed to upgrade.
+            thread.unwrap().join().unwrap();
+            assert_eq!(*x, "Hello");
+        });
+    }
+
+    #[test]
+    fn test_dangling_weak() {
+        let w = Weak::<String>::new();
+        assert!(w.upgrade().is_none());
+        assert!(w.clone().upgrade().is_none());
+    }
+
+    #[test]
+    fn test_slice() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(u16);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let x: Arc<[DetectDrop]> = (0..5).map(DetectDrop).collect();
+        let y = x.clone();
+        let w = Arc::downgrade(&y);
+        std::thread::spawn(move || assert_eq!(y[4].0, 4))
+            .join()
+            .unwrap();
+        assert_eq!(x.len(), 5);
+        assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+        let raw = Arc::into_raw(x);
+        let x = unsafe { Arc::from_raw(raw) };
+        assert_eq!(x.iter().map(|d| d.0).sum::<u16>(), 10);
+        drop(x);
+        assert_eq!(NUM_DROPS.load(Relaxed), 5);
+        assert!(w.upgrade().is_none());
+
+        let empty: Arc<[u64]> = Arc::from(Vec::new());
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_str() {
+        let x: Arc<str> = Arc::from("Hello");
+        let y: Arc<str> = Arc::from(String::from("World"));
+        assert_eq!(format!("{} {}", &*x, &*y), "Hello World");
+        assert_eq!(Arc::strong_count(&x.clone()), 2);
+    }
+
+    #[test]
+    fn test_dyn() {
+        let x = unsafe { Arc::coerce::<dyn Display>(Arc::new(123), |p| p) };
+        let y = unsafe { Arc::coerce::<dyn Display>(Arc::new("abc"), |p| p) };
+        let z = x.clone();
+        assert_eq!(format!("{}{}", &*x, &*y), "123abc");
+        assert!(Arc::ptr_eq(&x, &z));
+        assert!(!Arc::ptr_eq(&x, &y));
+
+        // A type with a larger alignment than the counters.
+        #[repr(align(64))]
+        struct Aligned(u8);
+
+        impl Display for Aligned {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        let a = unsafe { Arc::coerce::<dyn Display>(Arc::new(Aligned(7)), |p| p) };
+        let raw = Arc::into_raw(a);
+        assert_eq!(raw as *const u8 as usize % 64, 0);
+        let a = unsafe { Arc::from_raw(raw) };
+        assert_eq!(a.to_string(), "7");
+    }
 }
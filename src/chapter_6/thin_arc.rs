@@ -0,0 +1,345 @@
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{fence, AtomicUsize};
+
+// Like the `Arc` of `optimizing.rs`, but for a header followed by a slice,
+// such as a syntax tree node and its children, all in a single allocation:
+//
+//     data_ref_count | alloc_ref_count | header | len | item 0 | item 1 | ...
+//
+// Since the length is stored in the allocation itself, rather than next to
+// the pointer as in `Arc<[T]>`, a `ThinArc` is a single pointer wide.
+
+/// The part of the allocation before the items.
+#[repr(C)]
+struct Inner<H, T> {
+    /// Number of `ThinArc`s.
+    data_ref_count: AtomicUsize,
+    /// Number of `ThinWeak`s, plus one if there are any `ThinArc`s.
+    alloc_ref_count: AtomicUsize,
+    header: H,
+    len: usize,
+    items: [T; 0],
+}
+
+impl<H, T> Inner<H, T> {
+    fn layout(len: usize) -> Layout {
+        // The items start at `items`, which might be before the end of `Inner`'s padding.
+        let items = Layout::array::<T>(len).unwrap();
+        let size = mem::offset_of!(Self, items)
+            .checked_add(items.size())
+            .unwrap();
+        Layout::from_size_align(size, mem::align_of::<Self>())
+            .unwrap()
+            .pad_to_align()
+    }
+
+    // These only ever make references to single fields, since a `ThinWeak`
+    // can still look at the counters while the header and items are being dropped.
+
+    unsafe fn data_ref_count<'a>(ptr: NonNull<Self>) -> &'a AtomicUsize {
+        &(*ptr.as_ptr()).data_ref_count
+    }
+
+    unsafe fn alloc_ref_count<'a>(ptr: NonNull<Self>) -> &'a AtomicUsize {
+        &(*ptr.as_ptr()).alloc_ref_count
+    }
+
+    unsafe fn items(ptr: NonNull<Self>) -> *mut [T] {
+        let p = ptr.as_ptr();
+        ptr::slice_from_raw_parts_mut(ptr::addr_of_mut!((*p).items) as *mut T, (*p).len)
+    }
+}
+
+pub struct ThinArc<H, T> {
+    ptr: NonNull<Inner<H, T>>,
+}
+
+unsafe impl<H: Sync + Send, T: Sync + Send> Send for ThinArc<H, T> {}
+unsafe impl<H: Sync + Send, T: Sync + Send> Sync for ThinArc<H, T> {}
+
+pub struct ThinWeak<H, T> {
+    ptr: NonNull<Inner<H, T>>,
+}
+
+unsafe impl<H: Sync + Send, T: Sync + Send> Send for ThinWeak<H, T> {}
+unsafe impl<H: Sync + Send, T: Sync + Send> Sync for ThinWeak<H, T> {}
+
+/// Cleans up a half built `ThinArc` when the iterator runs out early or panics:
+/// drops the header and the items written so far, and frees the allocation.
+struct PartialInner<H, T> {
+    ptr: NonNull<Inner<H, T>>,
+    written: usize,
+}
+
+impl<H, T> Drop for PartialInner<H, T> {
+    fn drop(&mut self) {
+        unsafe {
+            let p = self.ptr.as_ptr();
+            ptr::drop_in_place(ptr::addr_of_mut!((*p).header));
+            let first = ptr::addr_of_mut!((*p).items) as *mut T;
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(first, self.written));
+            dealloc(p as *mut u8, Inner::<H, T>::layout((*p).len));
+        }
+    }
+}
+
+impl<H, T> ThinArc<H, T> {
+    /// Panics if `items` doesn't produce exactly as many items as it says,
+    /// after dropping everything, including the header.
+    #[allow(dead_code)]
+    pub fn new<I>(header: H, items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let mut items = items.into_iter();
+        let len = items.len();
+        let layout = Inner::<H, T>::layout(len);
+        let ptr = unsafe { alloc(layout) } as *mut Inner<H, T>;
+        let Some(ptr) = NonNull::new(ptr) else {
+            handle_alloc_error(layout);
+        };
+        let mut partial = PartialInner { ptr, written: 0 };
+        unsafe {
+            let p = ptr.as_ptr();
+            ptr::addr_of_mut!((*p).data_ref_count).write(AtomicUsize::new(1));
+            ptr::addr_of_mut!((*p).alloc_ref_count).write(AtomicUsize::new(1));
+            ptr::addr_of_mut!((*p).header).write(header);
+            ptr::addr_of_mut!((*p).len).write(len);
+            let first = ptr::addr_of_mut!((*p).items) as *mut T;
+            for i in 0..len {
+                let item = items.next().expect("iterator produced too few items");
+                first.add(i).write(item);
+                partial.written += 1;
+            }
+        }
+        mem::forget(partial);
+        // Complete now, so dropping it on the panic below frees everything.
+        let arc = ThinArc { ptr };
+        assert!(items.next().is_none(), "iterator produced too many items");
+        arc
+    }
+
+    #[allow(dead_code)]
+    pub fn header(&self) -> &H {
+        // Safety: Since there's a `ThinArc`, the header exists and may be shared.
+        unsafe { &(*self.ptr.as_ptr()).header }
+    }
+
+    #[allow(dead_code)]
+    pub fn items(&self) -> &[T] {
+        // Safety: Since there's a `ThinArc`, the items exist and may be shared.
+        unsafe { &*Inner::items(self.ptr) }
+    }
+
+    #[allow(dead_code)]
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    #[allow(dead_code)]
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.data_ref_count().load(Relaxed)
+    }
+
+    fn data_ref_count(&self) -> &AtomicUsize {
+        unsafe { Inner::data_ref_count(self.ptr) }
+    }
+
+    #[allow(dead_code)]
+    pub fn downgrade(arc: &Self) -> ThinWeak<H, T> {
+        // Unlike `Arc`, there's no `get_mut` that locks this counter.
+        let alloc_ref_count = unsafe { Inner::alloc_ref_count(arc.ptr) };
+        if alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        ThinWeak { ptr: arc.ptr }
+    }
+}
+
+impl<H, T> Clone for ThinArc<H, T> {
+    fn clone(&self) -> Self {
+        if self.data_ref_count().fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        ThinArc { ptr: self.ptr }
+    }
+}
+
+impl<H, T> Drop for ThinArc<H, T> {
+    fn drop(&mut self) {
+        if self.data_ref_count().fetch_sub(1, Release) == 1 {
+            fence(Acquire);
+            // Safety: The data reference counter is zero,
+            // so nothing will access the header and items anymore.
+            unsafe {
+                let p = self.ptr.as_ptr();
+                ptr::drop_in_place(ptr::addr_of_mut!((*p).header));
+                ptr::drop_in_place(Inner::items(self.ptr));
+            }
+            // Now that there's no `ThinArc`s left,
+            // drop the implicit weak pointer that represents all of them.
+            drop(ThinWeak { ptr: self.ptr });
+        }
+    }
+}
+
+impl<H, T> ThinWeak<H, T> {
+    #[allow(dead_code)]
+    pub fn upgrade(&self) -> Option<ThinArc<H, T>> {
+        let data_ref_count = unsafe { Inner::data_ref_count(self.ptr) };
+        let mut n = data_ref_count.load(Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            assert!(n < usize::MAX);
+            if let Err(e) = data_ref_count.compare_exchange_weak(n, n + 1, Relaxed, Relaxed) {
+                n = e;
+                continue;
+            }
+            return Some(ThinArc { ptr: self.ptr });
+        }
+    }
+
+    fn alloc_ref_count(&self) -> &AtomicUsize {
+        unsafe { Inner::alloc_ref_count(self.ptr) }
+    }
+}
+
+impl<H, T> Clone for ThinWeak<H, T> {
+    fn clone(&self) -> Self {
+        if self.alloc_ref_count().fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        ThinWeak { ptr: self.ptr }
+    }
+}
+
+impl<H, T> Drop for ThinWeak<H, T> {
+    fn drop(&mut self) {
+        if self.alloc_ref_count().fetch_sub(1, Release) == 1 {
+            fence(Acquire);
+            unsafe {
+                let len = (*self.ptr.as_ptr()).len;
+                dealloc(self.ptr.as_ptr() as *mut u8, Inner::<H, T>::layout(len));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+    use std::panic;
+    use std::thread;
+
+    #[test]
+    fn test_thin() {
+        assert_eq!(size_of::<ThinArc<String, u8>>(), size_of::<usize>());
+        assert_eq!(size_of::<Option<ThinArc<String, u8>>>(), size_of::<usize>());
+
+        let node = ThinArc::new("add", [1, 2, 3]);
+        assert_eq!(*node.header(), "add");
+        assert_eq!(node.items(), [1, 2, 3]);
+
+        let empty = ThinArc::new((), Vec::<u64>::new());
+        assert!(empty.items().is_empty());
+
+        // Items with a larger alignment than the header.
+        #[repr(align(64))]
+        struct Aligned(u8);
+
+        let aligned = ThinArc::new(1u8, [Aligned(2), Aligned(3)]);
+        assert_eq!(aligned.items().as_ptr() as usize % 64, 0);
+        assert_eq!(aligned.items()[1].0, 3);
+    }
+
+    #[test]
+    fn test_drop() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        let x = ThinArc::new(DetectDrop, (0..3).map(|_| DetectDrop));
+        let w = ThinArc::downgrade(&x);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let y = w.upgrade().unwrap();
+                        assert!(ThinArc::ptr_eq(&x, &y));
+                        drop(w.clone());
+                    }
+                });
+            }
+        });
+        assert_eq!(ThinArc::strong_count(&x), 1);
+        assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+        drop(x);
+        // The header and all three items.
+        assert_eq!(NUM_DROPS.load(Relaxed), 4);
+        assert!(w.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_wrong_len() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Relaxed);
+            }
+        }
+
+        /// Claims to produce `claimed` items, but produces `actual` ones.
+        struct Lying {
+            claimed: usize,
+            actual: usize,
+        }
+
+        impl Iterator for Lying {
+            type Item = DetectDrop;
+
+            fn next(&mut self) -> Option<DetectDrop> {
+                self.actual = self.actual.checked_sub(1)?;
+                Some(DetectDrop)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (self.claimed, Some(self.claimed))
+            }
+        }
+
+        impl ExactSizeIterator for Lying {}
+
+        let too_few = Lying {
+            claimed: 3,
+            actual: 2,
+        };
+        assert!(panic::catch_unwind(|| ThinArc::new(DetectDrop, too_few)).is_err());
+        // The header and both items.
+        assert_eq!(NUM_DROPS.load(Relaxed), 3);
+
+        let too_many = Lying {
+            claimed: 2,
+            actual: 3,
+        };
+        assert!(panic::catch_unwind(|| ThinArc::new(DetectDrop, too_many)).is_err());
+        // The header, both items, and the extra one.
+        assert_eq!(NUM_DROPS.load(Relaxed), 7);
+    }
+}
@@ -0,0 +1,50 @@
+use std::ops::{Deref, DerefMut};
+
+/// Aligns and pads a value to its own cache line, so two atomics that are
+/// written by different cores don't end up fighting over the same line.
+///
+/// Most x86-64 processors prefetch pairs of 64-byte lines, hence 128.
+#[derive(Default)]
+#[repr(align(128))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::{align_of, size_of};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    #[test]
+    fn test_cache_padded() {
+        struct Indices {
+            head: CachePadded<AtomicUsize>,
+            tail: CachePadded<AtomicUsize>,
+        }
+
+        assert_eq!(align_of::<CachePadded<u8>>(), 128);
+        assert_eq!(size_of::<Indices>(), 256);
+
+        let i = Indices {
+            head: CachePadded(AtomicUsize::new(1)),
+            tail: CachePadded::default(),
+        };
+        let head = &i.head as *const _ as usize;
+        let tail = &i.tail as *const _ as usize;
+        assert!(head.abs_diff(tail) >= 128);
+        assert_eq!(i.head.load(Relaxed), 1);
+    }
+}
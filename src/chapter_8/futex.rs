@@ -0,0 +1,119 @@
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+// The `atomic-wait` crate only offers an untimed `wait`.
+// On Linux, these go to the futex syscall directly, so we can give up waiting after a while.
+
+/// Blocks while `*a == expected`, but for no longer than `timeout`.
+/// Like `atomic_wait::wait`, this may also return spuriously,
+/// so callers need to check the value (and the time) again.
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            &timeout as *const libc::timespec,
+        );
+    }
+}
+
+/// Elsewhere, there's no portable timed wait, so this sleeps for a short while
+/// instead, which looks like a spurious wake-up to the caller.
+/// That's slower to react, but just as correct.
+#[cfg(not(target_os = "linux"))]
+#[allow(dead_code)]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    if a.load(std::sync::atomic::Ordering::Relaxed) == expected {
+        std::thread::sleep(timeout.min(Duration::from_millis(1)));
+    }
+}
+
+// Only on Linux: `FUTEX_PRIVATE_FLAG` lets the kernel assume only our own process uses the futex.
+// Without it, the futex is identified by the underlying memory instead of the
+// address, so it also works on memory that's mapped into multiple processes.
+
+/// Like `atomic_wait::wait`, but also works on memory shared with other processes.
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+pub fn wait_shared(a: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            std::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+/// Like `atomic_wait::wake_one`, but also wakes threads of other processes
+/// that are waiting in `wait_shared`.
+#[cfg(target_os = "linux")]
+#[allow(dead_code)]
+pub fn wake_one_shared(a: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, a as *const AtomicU32, libc::FUTEX_WAKE, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_wait::wake_one;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wait_timeout() {
+        let a = AtomicU32::new(0);
+
+        let start = Instant::now();
+        wait_timeout(&a, 0, Duration::from_millis(100));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Doesn't block at all if the value doesn't match.
+        let start = Instant::now();
+        wait_timeout(&a, 1, Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                a.store(1, Relaxed);
+                wake_one(&a);
+            });
+            let start = Instant::now();
+            while a.load(Relaxed) == 0 {
+                wait_timeout(&a, 0, Duration::from_secs(10));
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_wait_shared() {
+        let a = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                a.store(1, Relaxed);
+                wake_one_shared(&a);
+            });
+            while a.load(Relaxed) == 0 {
+                wait_shared(&a, 0);
+            }
+        });
+    }
+}
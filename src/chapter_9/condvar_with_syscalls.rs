@@ -4,13 +4,26 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
+#[cfg(feature = "lock_stats")]
+use super::lock_stats::{Counters, LockStats};
+#[cfg(feature = "lockdep")]
+use super::lockdep::LockClass;
+#[cfg(feature = "lock_stats")]
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "lock_stats")]
+use std::time::Instant;
+
 #[allow(dead_code)]
-struct Mutex<T> {
+pub struct Mutex<T> {
     /// 0: unlocked
     /// 1: locked
     /// 2: locked and waiting on other thread
     state: AtomicU32,
     value: UnsafeCell<T>,
+    #[cfg(feature = "lockdep")]
+    class: Option<&'static LockClass>,
+    #[cfg(feature = "lock_stats")]
+    stats: OnceLock<Arc<Counters>>,
 }
 
 unsafe impl<T> Sync for Mutex<T> where T: Send {}
@@ -21,23 +34,84 @@ impl<T> Mutex<T> {
         Self {
             state: AtomicU32::new(0), // 0: unlocked state
             value: UnsafeCell::new(value),
+            #[cfg(feature = "lockdep")]
+            class: None,
+            #[cfg(feature = "lock_stats")]
+            stats: OnceLock::new(),
+        }
+    }
+
+    /// Creates a mutex that shares its lock order with all others of the same `class`.
+    #[cfg(feature = "lockdep")]
+    #[allow(dead_code)]
+    pub const fn with_class(value: T, class: &'static LockClass) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+            class: Some(class),
+            #[cfg(feature = "lock_stats")]
+            stats: OnceLock::new(),
         }
     }
 
     #[allow(dead_code)]
-    pub fn lock(&self) -> MutexGuard<T> {
-        while self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        super::lockdep::acquire(self, self.class);
+        // `lock_contended` only returns once it has locked the mutex, so don't try again.
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+            #[cfg(feature = "deadlock_detection")]
+            super::deadlock::waiting(self);
+            #[cfg(not(feature = "lock_stats"))]
             lock_contended(&self.state);
+            #[cfg(feature = "lock_stats")]
+            {
+                let start = Instant::now();
+                lock_contended(&self.state, self.counters());
+                self.counters().contended(start.elapsed());
+            }
+        }
+        #[cfg(feature = "deadlock_detection")]
+        let acquisition = super::deadlock::acquired(self);
+        #[cfg(feature = "lock_stats")]
+        self.counters().acquired();
+        MutexGuard {
+            mutex: self,
+            #[cfg(feature = "deadlock_detection")]
+            acquisition,
+            #[cfg(feature = "lock_stats")]
+            locked_at: Instant::now(),
+        }
+    }
+
+    #[cfg(feature = "lock_stats")]
+    #[allow(dead_code)]
+    pub fn stats(&self) -> LockStats {
+        self.counters().stats()
+    }
+
+    #[cfg(feature = "lock_stats")]
+    fn counters(&self) -> &Counters {
+        self.stats.get_or_init(|| super::lock_stats::register(self))
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            super::lockdep::forget(self);
         }
-        MutexGuard { mutex: self }
     }
 }
 
-fn lock_contended(state: &AtomicU32) {
+fn lock_contended(state: &AtomicU32, #[cfg(feature = "lock_stats")] counters: &Counters) {
     let mut spin_count = 0;
 
     while state.load(Relaxed) == 1 && spin_count < 100 {
         spin_count += 1;
+        #[cfg(feature = "lock_stats")]
+        counters.spin();
         std::hint::spin_loop();
     }
 
@@ -46,12 +120,18 @@ fn lock_contended(state: &AtomicU32) {
     }
 
     while state.swap(2, Acquire) != 0 {
+        #[cfg(feature = "lock_stats")]
+        counters.wait();
         wait(state, 2);
     }
 }
 
-struct MutexGuard<'a, T> {
+pub struct MutexGuard<'a, T> {
     mutex: &'a Mutex<T>,
+    #[cfg(feature = "deadlock_detection")]
+    acquisition: super::deadlock::AcquisitionId,
+    #[cfg(feature = "lock_stats")]
+    locked_at: Instant,
 }
 
 impl<T> Deref for MutexGuard<'_, T> {
@@ -69,7 +149,15 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "deadlock_detection")]
+        super::deadlock::released(self.mutex, &self.acquisition);
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.mutex);
+        #[cfg(feature = "lock_stats")]
+        self.mutex.counters().released(self.locked_at.elapsed());
         if self.mutex.state.swap(0, Release) == 2 {
+            #[cfg(feature = "lock_stats")]
+            self.mutex.counters().wake();
             wake_one(&self.mutex.state);
         }
     }
@@ -142,4 +230,23 @@ mod tests {
         });
         assert!(wakeups < 10);
     }
+
+    #[test]
+    fn test_contended_lock() {
+        // Leaked, so a thread that never gets out of `lock()` can't keep us from failing.
+        let mutex: &'static Mutex<i32> = Box::leak(Box::new(Mutex::new(0)));
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let guard = mutex.lock();
+        thread::spawn(move || {
+            *mutex.lock() += 1;
+            tx.send(()).unwrap();
+        });
+        // Make sure the other thread finds it locked.
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+
+        assert!(rx.recv_timeout(Duration::from_secs(10)).is_ok());
+        assert_eq!(*mutex.lock(), 1);
+    }
 }
@@ -0,0 +1,380 @@
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread::{self, JoinHandle, ThreadId};
+use std::time::Duration;
+
+// A global wait-for graph for our `Mutex` and `RwLock`.
+//
+// Locks tell us when a thread starts waiting on them, when a thread got them,
+// and when it let go of them. A deadlock is a cycle in this graph:
+// a thread waiting on a lock held by a thread waiting on a lock held by ... the first thread.
+//
+// The graph is updated before a lock is released and after it's acquired,
+// so it might miss a holder for a moment, but never lists one that's already gone.
+// That way, `check()` can't report a deadlock that isn't real.
+//
+// It uses the standard library's `Mutex`, to not end up detecting itself.
+
+static GRAPH: LazyLock<Mutex<Graph>> = LazyLock::new(|| Mutex::new(Graph::default()));
+
+#[derive(Default)]
+struct Graph {
+    /// The threads holding each lock, by address.
+    /// More than one for read-locked `RwLock`s.
+    holders: HashMap<usize, Vec<Acquisition>>,
+    /// The lock each thread is blocked on.
+    waiting: HashMap<ThreadId, Acquisition>,
+}
+
+/// Identifies an acquisition, so its guard can release exactly that one,
+/// even from another thread, and even if other threads hold the same lock.
+pub struct AcquisitionId(u64);
+
+#[derive(Clone)]
+struct Acquisition {
+    id: u64,
+    lock: usize,
+    thread: ThreadId,
+    thread_name: Option<String>,
+    backtrace: Arc<Backtrace>,
+}
+
+impl Acquisition {
+    fn new(lock: usize) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let thread = thread::current();
+        Self {
+            id: NEXT_ID.fetch_add(1, Relaxed),
+            lock,
+            thread: thread.id(),
+            thread_name: thread.name().map(String::from),
+            // Only captured if enabled through `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+            backtrace: Arc::new(Backtrace::capture()),
+        }
+    }
+}
+
+fn graph() -> std::sync::MutexGuard<'static, Graph> {
+    // A panic while holding this can't leave the graph in a broken state.
+    GRAPH.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Called by a lock when the current thread is about to block on it.
+pub fn waiting<L>(lock: &L) {
+    let lock = lock as *const L as usize;
+    let mut graph = graph();
+    if graph.waiting.get(&thread::current().id()).map(|w| w.lock) != Some(lock) {
+        let waiting = Acquisition::new(lock);
+        graph.waiting.insert(waiting.thread, waiting);
+    }
+}
+
+/// Called by a lock when the current thread got it.
+/// The result goes in the guard, to pass to `released()`.
+pub fn acquired<L>(lock: &L) -> AcquisitionId {
+    let lock = lock as *const L as usize;
+    let mut graph = graph();
+    let acquisition = match graph.waiting.remove(&thread::current().id()) {
+        // Keep the backtrace we already have.
+        Some(waiting) if waiting.lock == lock => waiting,
+        _ => Acquisition::new(lock),
+    };
+    let id = AcquisitionId(acquisition.id);
+    graph.holders.entry(lock).or_default().push(acquisition);
+    id
+}
+
+/// Called by a guard right before it releases its lock,
+/// on whichever thread it's dropped.
+pub fn released<L>(lock: &L, id: &AcquisitionId) {
+    let lock = lock as *const L as usize;
+    let mut graph = graph();
+    let Some(holders) = graph.holders.get_mut(&lock) else {
+        return;
+    };
+    let Some(i) = holders.iter().position(|h| h.id == id.0) else {
+        return;
+    };
+    holders.swap_remove(i);
+    if holders.is_empty() {
+        graph.holders.remove(&lock);
+    }
+}
+
+/// A set of threads that are all waiting on each other.
+#[allow(dead_code)]
+pub struct Deadlock {
+    pub threads: Vec<DeadlockedThread>,
+}
+
+#[allow(dead_code)]
+pub struct DeadlockedThread {
+    pub thread_id: ThreadId,
+    pub thread_name: Option<String>,
+    /// The address of the lock this thread is waiting on.
+    pub waiting_on: usize,
+    pub waiting_backtrace: Arc<Backtrace>,
+    /// The addresses of the locks this thread holds, and where they were acquired.
+    pub holding: Vec<(usize, Arc<Backtrace>)>,
+}
+
+/// Looks for cycles in the wait-for graph.
+#[allow(dead_code)]
+pub fn check() -> Vec<Deadlock> {
+    let graph = graph();
+
+    let mut held_by: HashMap<ThreadId, Vec<&Acquisition>> = HashMap::new();
+    for a in graph.holders.values().flatten() {
+        held_by.entry(a.thread).or_default().push(a);
+    }
+
+    // The threads each waiting thread is waiting for.
+    let waits_for = |t: &ThreadId| -> Vec<ThreadId> {
+        graph.waiting.get(t).map_or(Vec::new(), |w| {
+            graph
+                .holders
+                .get(&w.lock)
+                .map_or(Vec::new(), |h| h.iter().map(|a| a.thread).collect())
+        })
+    };
+
+    let mut deadlocks = Vec::new();
+    let mut reported: Vec<ThreadId> = Vec::new();
+
+    for &start in graph.waiting.keys() {
+        if reported.contains(&start) {
+            continue;
+        }
+        // Depth-first search for a path leading back to a thread on the current path.
+        let mut path = vec![start];
+        let mut todo = vec![waits_for(&start)];
+        while let Some(next) = todo.last_mut() {
+            let Some(t) = next.pop() else {
+                todo.pop();
+                path.pop();
+                continue;
+            };
+            if let Some(i) = path.iter().position(|&p| p == t) {
+                let cycle = &path[i..];
+                if !cycle.iter().any(|t| reported.contains(t)) {
+                    reported.extend_from_slice(cycle);
+                    deadlocks.push(Deadlock {
+                        threads: cycle
+                            .iter()
+                            .map(|t| {
+                                let w = &graph.waiting[t];
+                                DeadlockedThread {
+                                    thread_id: *t,
+                                    thread_name: w.thread_name.clone(),
+                                    waiting_on: w.lock,
+                                    waiting_backtrace: w.backtrace.clone(),
+                                    holding: held_by
+                                        .get(t)
+                                        .into_iter()
+                                        .flatten()
+                                        .map(|a| (a.lock, a.backtrace.clone()))
+                                        .collect(),
+                                }
+                            })
+                            .collect(),
+                    });
+                }
+                continue;
+            }
+            if graph.waiting.contains_key(&t) {
+                path.push(t);
+                todo.push(waits_for(&t));
+            }
+        }
+    }
+
+    deadlocks
+}
+
+/// Spawns a thread that runs `check()` every `interval`,
+/// and calls `on_deadlock` when it finds any.
+#[allow(dead_code)]
+pub fn spawn_detector<F>(interval: Duration, on_deadlock: F) -> JoinHandle<()>
+where
+    F: Fn(&[Deadlock]) + Send + 'static,
+{
+    thread::Builder::new()
+        .name("deadlock detector".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let deadlocks = check();
+            if !deadlocks.is_empty() {
+                on_deadlock(&deadlocks);
+            }
+        })
+        .unwrap()
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "deadlock between {} threads:", self.threads.len())?;
+        for t in &self.threads {
+            writeln!(
+                f,
+                "thread {:?} ({}) is waiting on lock {:#x}",
+                t.thread_id,
+                t.thread_name.as_deref().unwrap_or("unnamed"),
+                t.waiting_on,
+            )?;
+            writeln!(f, "{}", t.waiting_backtrace)?;
+            for (lock, backtrace) in &t.holding {
+                writeln!(f, "  while holding lock {lock:#x}, acquired at:")?;
+                writeln!(f, "{backtrace}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::condvar_with_syscalls::Mutex;
+    use super::super::rwlock_no_writer_stravation::RwLock;
+    use super::*;
+    use std::sync::Barrier;
+
+    fn find(deadlocks: &[Deadlock], lock: usize) -> Option<&Deadlock> {
+        deadlocks
+            .iter()
+            .find(|d| d.threads.iter().any(|t| t.waiting_on == lock))
+    }
+
+    #[test]
+    fn test_no_deadlock() {
+        let a = Mutex::new(0);
+        let b = RwLock::new(0);
+        let a_addr = &a as *const _ as usize;
+        let b_addr = &b as *const _ as usize;
+
+        thread::scope(|s| {
+            for _ in 0..10 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let _a = a.lock();
+                        let _b = b.write();
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let _b = b.read();
+                        let deadlocks = check();
+                        assert!(find(&deadlocks, a_addr).is_none());
+                        assert!(find(&deadlocks, b_addr).is_none());
+                    }
+                });
+            }
+        });
+
+        assert!(find(&check(), a_addr).is_none());
+    }
+
+    #[test]
+    #[cfg_attr(
+        feature = "lockdep",
+        ignore = "lockdep panics before this can deadlock"
+    )]
+    fn test_abba_deadlock() {
+        // Leaked, since the deadlocked threads never let go of them.
+        let a: &'static Mutex<i32> = Box::leak(Box::new(Mutex::new(0)));
+        let b: &'static RwLock<i32> = Box::leak(Box::new(RwLock::new(0)));
+        let barrier = Arc::new(Barrier::new(2));
+
+        let barrier2 = barrier.clone();
+        thread::Builder::new()
+            .name("ab".into())
+            .spawn(move || {
+                let _a = a.lock();
+                barrier2.wait();
+                let _b = b.write();
+            })
+            .unwrap();
+        thread::spawn(move || {
+            let _b = b.read();
+            barrier.wait();
+            let _a = a.lock();
+        });
+
+        let a_addr = a as *const _ as usize;
+        let b_addr = b as *const _ as usize;
+
+        let deadlocks = loop {
+            let deadlocks = check();
+            if find(&deadlocks, a_addr).is_some() {
+                break deadlocks;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let deadlock = find(&deadlocks, a_addr).unwrap();
+        assert_eq!(deadlock.threads.len(), 2);
+        let ab = deadlock
+            .threads
+            .iter()
+            .find(|t| t.thread_name.as_deref() == Some("ab"))
+            .unwrap();
+        assert_eq!(ab.waiting_on, b_addr);
+        assert_eq!(ab.holding.len(), 1);
+        assert_eq!(ab.holding[0].0, a_addr);
+        assert!(deadlock
+            .to_string()
+            .starts_with("deadlock between 2 threads"));
+    }
+
+    #[test]
+    fn test_guard_dropped_elsewhere() {
+        let lock = RwLock::new(0);
+        let addr = &lock as *const _ as usize;
+        let holders =
+            || -> Vec<ThreadId> { graph().holders[&addr].iter().map(|a| a.thread).collect() };
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+
+        thread::scope(|s| {
+            let lock = &lock;
+            let other = s.spawn(move || {
+                let _r = lock.read();
+                locked_tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+            });
+            locked_rx.recv().unwrap();
+            let r = lock.read();
+            assert_eq!(holders().len(), 2);
+            // Drop our read guard on a third thread, which has no entry of its own.
+            s.spawn(move || drop(r)).join().unwrap();
+            // Only the other reader is left, not us.
+            assert_eq!(holders(), [other.thread().id()]);
+            done_tx.send(()).unwrap();
+        });
+        assert!(!graph().holders.contains_key(&addr));
+    }
+
+    #[test]
+    fn test_detector_thread() {
+        let a: &'static Mutex<i32> = Box::leak(Box::new(Mutex::new(0)));
+        let a_addr = a as *const _ as usize;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        spawn_detector(Duration::from_millis(10), move |deadlocks| {
+            if find(deadlocks, a_addr).is_some() {
+                let _ = tx.send(());
+            }
+        });
+
+        // A thread locking the same mutex twice deadlocks on itself.
+        thread::spawn(move || {
+            let _g1 = a.lock();
+            let _g2 = a.lock();
+        });
+
+        rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
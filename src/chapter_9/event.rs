@@ -0,0 +1,203 @@
+use crate::chapter_8::futex::wait_timeout;
+use atomic_wait::{wait, wake_all, wake_one};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::time::{Duration, Instant};
+
+/// A flag that threads can wait on until it's set.
+///
+/// A manual-reset event stays set, releasing all waiters, until `reset()` is called.
+/// An auto-reset event releases a single waiter, and goes back to unset when it does.
+#[allow(dead_code)]
+pub struct Event {
+    /// 0: unset
+    /// 1: set
+    /// 2: unset and maybe other threads waiting
+    state: AtomicU32,
+    auto_reset: bool,
+}
+
+impl Event {
+    #[allow(dead_code)]
+    pub const fn manual_reset() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            auto_reset: false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub const fn auto_reset() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            auto_reset: true,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_set(&self) -> bool {
+        self.state.load(Relaxed) == 1
+    }
+
+    #[allow(dead_code)]
+    pub fn set(&self) {
+        if self.state.swap(1, Release) == 2 {
+            if self.auto_reset {
+                wake_one(&self.state);
+            } else {
+                wake_all(&self.state);
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn reset(&self) {
+        // Only 1 needs to change, to not forget about waiting threads.
+        let _ = self.state.compare_exchange(1, 0, Relaxed, Relaxed);
+    }
+
+    #[allow(dead_code)]
+    pub fn wait(&self) {
+        let mut waited = false;
+        while !self.try_take(waited) {
+            wait(&self.state, 2);
+            waited = true;
+        }
+    }
+
+    /// Returns `false` if the event wasn't set within `timeout`.
+    /// A timeout too far in the future to represent waits forever.
+    #[allow(dead_code)]
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now().checked_add(timeout);
+        let mut waited = false;
+        while !self.try_take(waited) {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    wait_timeout(&self.state, 2, deadline - now);
+                }
+                None => wait(&self.state, 2),
+            }
+            waited = true;
+        }
+        true
+    }
+
+    /// Returns `true` if the event is set, consuming it for an auto-reset event.
+    /// Otherwise, marks the state as having waiters so we can go to sleep.
+    fn try_take(&self, waited: bool) -> bool {
+        let mut s = self.state.load(Acquire);
+        loop {
+            if s == 1 {
+                if !self.auto_reset {
+                    return true;
+                }
+                // If we've been sleeping, there might be others sleeping too,
+                // so they still need a wake up on the next `set()`.
+                let new = if waited { 2 } else { 0 };
+                match self.state.compare_exchange(1, new, Acquire, Relaxed) {
+                    Ok(_) => return true,
+                    Err(e) => s = e,
+                }
+            } else if s == 0 {
+                match self.state.compare_exchange(0, 2, Relaxed, Relaxed) {
+                    Ok(_) => return false,
+                    Err(e) => s = e,
+                }
+            } else {
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    #[test]
+    fn test_manual_reset() {
+        let event = Event::manual_reset();
+        let num_woken = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..5 {
+                s.spawn(|| {
+                    event.wait();
+                    num_woken.fetch_add(1, Relaxed);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(num_woken.load(Relaxed), 0);
+            event.set();
+        });
+
+        assert_eq!(num_woken.load(Relaxed), 5);
+        // Stays set until reset.
+        assert!(event.is_set());
+        event.wait();
+        event.reset();
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_auto_reset() {
+        let event = Event::auto_reset();
+        let num_woken = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    event.wait();
+                    num_woken.fetch_add(1, Relaxed);
+                });
+            }
+            for i in 1..=3 {
+                thread::sleep(Duration::from_millis(50));
+                event.set();
+                // Every set releases exactly one waiter.
+                while num_woken.load(Relaxed) < i {
+                    thread::yield_now();
+                }
+                thread::sleep(Duration::from_millis(10));
+                assert_eq!(num_woken.load(Relaxed), i);
+            }
+        });
+
+        assert!(!event.is_set());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let event = Event::auto_reset();
+
+        let start = Instant::now();
+        assert!(!event.wait_timeout(Duration::from_millis(100)));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                event.set();
+            });
+            assert!(event.wait_timeout(Duration::from_secs(10)));
+        });
+
+        // It was consumed by the wait.
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                event.set();
+            });
+            assert!(event.wait_timeout(Duration::MAX));
+        });
+    }
+}
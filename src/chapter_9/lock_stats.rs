@@ -0,0 +1,248 @@
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+// Contention statistics for our `Mutex` and `RwLock`.
+//
+// Every lock gets its counters on first use, and registers them globally,
+// so `report()` can show which locks are the most contended.
+// The registry only keeps weak references, so dropped locks disappear from it.
+
+/// The counters of a single lock, only ever updated with relaxed atomics.
+#[derive(Default)]
+pub struct Counters {
+    name: String,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+    waits: AtomicU64,
+    wakes: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+    hold_nanos: AtomicU64,
+    max_hold_nanos: AtomicU64,
+}
+
+impl Counters {
+    pub fn acquired(&self) {
+        self.acquisitions.fetch_add(1, Relaxed);
+    }
+
+    /// The lock was acquired, but only after waiting for it for `wait_time`.
+    pub fn contended(&self, wait_time: Duration) {
+        let nanos = wait_time.as_nanos() as u64;
+        self.contended.fetch_add(1, Relaxed);
+        self.wait_nanos.fetch_add(nanos, Relaxed);
+        self.max_wait_nanos.fetch_max(nanos, Relaxed);
+    }
+
+    pub fn spin(&self) {
+        self.spins.fetch_add(1, Relaxed);
+    }
+
+    /// About to call `wait()` on a futex.
+    pub fn wait(&self) {
+        self.waits.fetch_add(1, Relaxed);
+    }
+
+    /// About to call `wake_one()` or `wake_all()` on a futex.
+    pub fn wake(&self) {
+        self.wakes.fetch_add(1, Relaxed);
+    }
+
+    pub fn released(&self, hold_time: Duration) {
+        let nanos = hold_time.as_nanos() as u64;
+        self.hold_nanos.fetch_add(nanos, Relaxed);
+        self.max_hold_nanos.fetch_max(nanos, Relaxed);
+    }
+
+    pub fn stats(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Relaxed),
+            contended: self.contended.load(Relaxed),
+            spins: self.spins.load(Relaxed),
+            waits: self.waits.load(Relaxed),
+            wakes: self.wakes.load(Relaxed),
+            total_wait: Duration::from_nanos(self.wait_nanos.load(Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait_nanos.load(Relaxed)),
+            total_hold: Duration::from_nanos(self.hold_nanos.load(Relaxed)),
+            max_hold: Duration::from_nanos(self.max_hold_nanos.load(Relaxed)),
+        }
+    }
+}
+
+/// A snapshot of the counters of a lock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    /// Acquisitions that couldn't immediately take the lock.
+    pub contended: u64,
+    /// Iterations of the spin loop before giving up and waiting.
+    pub spins: u64,
+    /// Calls to futex `wait()`.
+    pub waits: u64,
+    /// Calls to futex `wake_one()` or `wake_all()`.
+    pub wakes: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub total_hold: Duration,
+    pub max_hold: Duration,
+}
+
+static REGISTRY: Mutex<Vec<Weak<Counters>>> = Mutex::new(Vec::new());
+
+/// Creates the counters for `lock`, and adds them to the registry.
+pub fn register<L>(lock: &L) -> Arc<Counters> {
+    let counters = Arc::new(Counters {
+        name: format!(
+            "{} at {:#x}",
+            std::any::type_name::<L>(),
+            lock as *const L as usize
+        ),
+        ..Counters::default()
+    });
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|c| c.strong_count() > 0);
+    registry.push(Arc::downgrade(&counters));
+    counters
+}
+
+/// The stats of all living locks, the most contended first.
+#[allow(dead_code)]
+pub fn all() -> Vec<(String, LockStats)> {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let mut all: Vec<(String, LockStats)> = registry
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|c| (c.name.clone(), c.stats()))
+        .collect();
+    drop(registry);
+    all.sort_by(|(_, a), (_, b)| {
+        (b.total_wait, b.contended, b.acquisitions).cmp(&(
+            a.total_wait,
+            a.contended,
+            a.acquisitions,
+        ))
+    });
+    all
+}
+
+/// A human readable report of the `n` most contended locks.
+#[allow(dead_code)]
+pub fn report(n: usize) -> String {
+    let mut report = String::new();
+    for (name, s) in all().into_iter().take(n) {
+        let _ = writeln!(
+            report,
+            "{name}: {} acquisitions, {} contended, {} spins, {} waits, {} wakes, \
+             wait {:?} (max {:?}), hold {:?} (max {:?})",
+            s.acquisitions,
+            s.contended,
+            s.spins,
+            s.waits,
+            s.wakes,
+            s.total_wait,
+            s.max_wait,
+            s.total_hold,
+            s.max_hold,
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::condvar_with_syscalls::Mutex;
+    use super::super::rwlock_no_writer_stravation::RwLock;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_uncontended() {
+        let mutex = Mutex::new(0);
+        for _ in 0..10 {
+            *mutex.lock() += 1;
+        }
+
+        let stats = mutex.stats();
+        assert_eq!(stats.acquisitions, 10);
+        assert_eq!(stats.contended, 0);
+        assert_eq!(stats.waits, 0);
+        assert_eq!(stats.wakes, 0);
+        assert_eq!(stats.total_wait, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_contended_mutex() {
+        let mutex = Mutex::new(0);
+
+        thread::scope(|s| {
+            let guard = mutex.lock();
+            s.spawn(|| {
+                *mutex.lock() += 1;
+            });
+            thread::sleep(Duration::from_millis(100));
+            drop(guard);
+        });
+
+        let stats = mutex.stats();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contended, 1);
+        assert!(stats.spins > 0);
+        assert!(stats.waits >= 1);
+        assert!(stats.wakes >= 1);
+        assert!(stats.max_hold >= Duration::from_millis(100));
+        assert!(stats.max_wait > Duration::ZERO);
+        assert!(stats.total_hold >= stats.max_hold);
+    }
+
+    #[test]
+    fn test_contended_rwlock() {
+        let rwlock = RwLock::new(0);
+
+        thread::scope(|s| {
+            let guard = rwlock.read();
+            s.spawn(|| {
+                *rwlock.write() += 1;
+            });
+            thread::sleep(Duration::from_millis(100));
+            drop(guard);
+        });
+        assert_eq!(*rwlock.read(), 1);
+
+        let stats = rwlock.stats();
+        assert_eq!(stats.acquisitions, 3);
+        assert_eq!(stats.contended, 1);
+        assert!(stats.waits >= 1);
+        assert!(stats.wakes >= 1);
+        assert!(stats.max_wait >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_report() {
+        let hot = Mutex::new(0);
+        let cold = Mutex::new(0);
+
+        thread::scope(|s| {
+            let guard = hot.lock();
+            s.spawn(|| {
+                *hot.lock() += 1;
+            });
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        *cold.lock() += 1;
+
+        let hot_name = format!("at {:#x}", &hot as *const _ as usize);
+        let cold_name = format!("at {:#x}", &cold as *const _ as usize);
+        let all = all();
+        let hot_pos = all.iter().position(|(n, _)| n.ends_with(&hot_name));
+        let cold_pos = all.iter().position(|(n, _)| n.ends_with(&cold_name));
+        assert!(hot_pos.unwrap() < cold_pos.unwrap());
+
+        let report = report(usize::MAX);
+        assert!(report.contains(&hot_name));
+    }
+}
@@ -0,0 +1,301 @@
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread::{self, ThreadId};
+
+// A lock order validator, like the Linux kernel's lockdep.
+//
+// Every lock belongs to a class. Whenever a thread locks something while already
+// holding other locks, we remember that those classes come before the new one.
+// If we ever see the opposite order, even in another thread and even if that
+// didn't deadlock this time, we complain: with the right timing, it will.
+//
+// Locks share a class when constructed with the same `LockClass`.
+// Otherwise, every lock is its own class.
+
+/// A named lock class, to be used as a `static`.
+pub struct LockClass {
+    name: &'static str,
+}
+
+impl LockClass {
+    #[allow(dead_code)]
+    pub const fn new(name: &'static str) -> Self {
+        Self { name }
+    }
+}
+
+/// Whether to panic on a lock order inversion, rather than print a report.
+static PANIC: AtomicBool = AtomicBool::new(true);
+
+#[allow(dead_code)]
+pub fn set_panic_on_violation(panic: bool) {
+    PANIC.store(panic, Relaxed);
+}
+
+static GRAPH: LazyLock<Mutex<Graph>> = LazyLock::new(|| Mutex::new(Graph::default()));
+
+#[derive(Default)]
+struct Graph {
+    /// For every class, the classes that have been locked while holding it,
+    /// and where that first happened.
+    after: HashMap<usize, HashMap<usize, Arc<Backtrace>>>,
+    names: HashMap<usize, String>,
+    /// Inversions we've already complained about.
+    reported: HashSet<(usize, usize)>,
+    /// The locks held by each thread, in locking order.
+    held: HashMap<ThreadId, Vec<Held>>,
+}
+
+struct Held {
+    /// The address of the lock itself, to find it again on release.
+    lock: usize,
+    class: usize,
+}
+
+impl Graph {
+    /// Finds a chain of classes leading from `from` to `to`.
+    fn path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut visited = HashSet::new();
+        let mut path = vec![from];
+        let mut todo = vec![self.after_keys(from)];
+        while let Some(next) = todo.last_mut() {
+            let Some(c) = next.pop() else {
+                todo.pop();
+                path.pop();
+                continue;
+            };
+            if c == to {
+                path.push(c);
+                return Some(path);
+            }
+            if visited.insert(c) {
+                path.push(c);
+                todo.push(self.after_keys(c));
+            }
+        }
+        None
+    }
+
+    fn after_keys(&self, class: usize) -> Vec<usize> {
+        self.after
+            .get(&class)
+            .map_or(Vec::new(), |a| a.keys().copied().collect())
+    }
+
+    fn name(&self, class: usize) -> &str {
+        self.names.get(&class).map_or("?", |n| n)
+    }
+}
+
+fn key<L>(lock: &L, class: Option<&'static LockClass>) -> usize {
+    match class {
+        Some(class) => class as *const LockClass as usize,
+        None => lock as *const L as usize,
+    }
+}
+
+/// Called by a lock before the current thread tries to lock it.
+pub fn acquire<L>(lock: &L, class: Option<&'static LockClass>) {
+    let new = key(lock, class);
+    let thread = thread::current().id();
+
+    let mut graph = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+    let held: Vec<usize> = graph
+        .held
+        .get(&thread)
+        .map_or(Vec::new(), |h| h.iter().map(|h| h.class).collect());
+    graph.names.entry(new).or_insert_with(|| match class {
+        Some(class) => class.name.to_string(),
+        None => format!("{} at {:#x}", std::any::type_name::<L>(), new),
+    });
+
+    let mut report = None;
+    for &old in &held {
+        if old == new {
+            continue;
+        }
+        if let Some(path) = graph.path(new, old) {
+            if report.is_none() && graph.reported.insert((old, new)) {
+                report = Some(inversion_report(&graph, old, new, &path));
+            }
+            continue;
+        }
+        let entry = graph.after.entry(old).or_default();
+        entry
+            .entry(new)
+            .or_insert_with(|| Arc::new(Backtrace::capture()));
+    }
+    // If we panic, the lock isn't taken, so there's nothing to release later.
+    let panic = report.is_some() && PANIC.load(Relaxed);
+    if !panic {
+        graph.held.entry(thread).or_default().push(Held {
+            lock: lock as *const L as usize,
+            class: new,
+        });
+    }
+    drop(graph);
+
+    if let Some(report) = report {
+        if panic {
+            panic!("{report}");
+        }
+        eprintln!("{report}");
+    }
+}
+
+/// Called by a lock when it's released, by whichever thread drops the guard.
+pub fn release<L>(lock: &L) {
+    let lock = lock as *const L as usize;
+    let mut graph = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+    // Guards might be dropped on another thread than the one that locked,
+    // so look for the lock in the current thread first, and then in all others.
+    let current = thread::current().id();
+    let thread = match graph.held.get(&current) {
+        Some(h) if h.iter().any(|h| h.lock == lock) => current,
+        _ => match graph
+            .held
+            .iter()
+            .find(|(_, h)| h.iter().any(|h| h.lock == lock))
+        {
+            Some((&t, _)) => t,
+            None => return,
+        },
+    };
+    let held = graph.held.get_mut(&thread).unwrap();
+    let i = held.iter().rposition(|h| h.lock == lock).unwrap();
+    held.remove(i);
+    if held.is_empty() {
+        graph.held.remove(&thread);
+    }
+}
+
+/// Called when a lock without a `LockClass` is dropped,
+/// so a new lock at the same address doesn't inherit its ordering.
+pub fn forget<L>(lock: &L) {
+    let k = lock as *const L as usize;
+    let mut graph = GRAPH.lock().unwrap_or_else(|e| e.into_inner());
+    graph.after.remove(&k);
+    for after in graph.after.values_mut() {
+        after.remove(&k);
+    }
+    graph.names.remove(&k);
+    graph.reported.retain(|&(a, b)| a != k && b != k);
+}
+
+fn inversion_report(graph: &Graph, old: usize, new: usize, path: &[usize]) -> String {
+    let mut report = format!(
+        "lock order inversion: locking `{}` while holding `{}`, but the opposite order was seen before:\n",
+        graph.name(new),
+        graph.name(old),
+    );
+    for pair in path.windows(2) {
+        report += &format!(
+            "`{}` was locked while holding `{}` at:\n{}\n",
+            graph.name(pair[1]),
+            graph.name(pair[0]),
+            graph.after[&pair[0]][&pair[1]],
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::condvar_with_syscalls::Mutex;
+    use super::super::rwlock_no_writer_stravation::RwLock;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_consistent_order() {
+        let a = Mutex::new(0);
+        let b = RwLock::new(0);
+        let c = Mutex::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let _a = a.lock();
+                        let _b = b.read();
+                        let _c = c.lock();
+                    }
+                    let _a = a.lock();
+                    let _c = c.lock();
+                });
+            }
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_abba() {
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+
+        // This never deadlocks, but could have if these threads ran at the same time.
+        thread::scope(|s| {
+            s.spawn(|| {
+                let _a = a.lock();
+                let _b = b.lock();
+            });
+        });
+        let _b = b.lock();
+        let _a = a.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_indirect_inversion() {
+        let a = Mutex::new(0);
+        let b = RwLock::new(0);
+        let c = Mutex::new(0);
+
+        {
+            let _a = a.lock();
+            let _b = b.write();
+        }
+        {
+            let _b = b.read();
+            let _c = c.lock();
+        }
+        let _c = c.lock();
+        let _a = a.lock();
+    }
+
+    #[test]
+    fn test_guard_dropped_elsewhere() {
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+
+        let guard = a.lock();
+        thread::scope(|s| {
+            s.spawn(move || drop(guard));
+        });
+        // `a` isn't held anymore, so this doesn't establish any order.
+        let _b = b.lock();
+        let _a = a.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order inversion")]
+    fn test_lock_classes() {
+        static OUTER: LockClass = LockClass::new("outer");
+        static INNER: LockClass = LockClass::new("inner");
+
+        // The order was seen on other instances of the same classes.
+        {
+            let a1 = Mutex::with_class(0, &OUTER);
+            let b1 = RwLock::with_class(0, &INNER);
+            let _a = a1.lock();
+            let _b = b1.write();
+        }
+        let a2 = Mutex::with_class(0, &OUTER);
+        let b2 = RwLock::with_class(0, &INNER);
+        let _b = b2.read();
+        let _a = a2.lock();
+    }
+}
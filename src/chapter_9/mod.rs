@@ -1,7 +1,15 @@
 mod condvar_no_syscalls;
 mod condvar_with_syscalls;
+#[cfg(feature = "deadlock_detection")]
+mod deadlock;
+mod event;
+#[cfg(feature = "lock_stats")]
+mod lock_stats;
+#[cfg(feature = "lockdep")]
+mod lockdep;
 mod mutex_no_syscalls;
 mod mutex_with_syscalls;
 mod rwlock;
 mod rwlock_no_busy_loop;
-mod rwlock_no_writer_stravation;
+pub mod rwlock_no_writer_stravation;
+mod wait_group;
@@ -1,135 +1,275 @@
-#[cfg(test)]
-mod tests {
-    use atomic_wait::{wait, wake_all, wake_one};
-    use std::cell::UnsafeCell;
-    use std::ops::{Deref, DerefMut};
-    use std::sync::atomic::AtomicU32;
-    use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-    use std::thread;
+use atomic_wait::{wait, wake_all, wake_one};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
-    struct RwLock<T> {
-        /// The number of read locks times two, plus one if there's a writer waiting.
-        /// u32::MAX if writer locked.
-        ///
-        /// This means that readers may acquire the lock when
-        /// the state is even, but need to block when odd.
-        state: AtomicU32,
-        /// Incremented to wake up the writers.
-        writer_wake_counter: AtomicU32,
-        data: UnsafeCell<T>,
-    }
+#[cfg(feature = "lock_stats")]
+use super::lock_stats::{Counters, LockStats};
+#[cfg(feature = "lockdep")]
+use super::lockdep::LockClass;
+#[cfg(feature = "lock_stats")]
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "lock_stats")]
+use std::time::Instant;
 
-    unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+#[allow(dead_code)]
+pub struct RwLock<T> {
+    /// The number of read locks times two, plus one if there's a writer waiting.
+    /// u32::MAX if writer locked.
+    ///
+    /// This means that readers may acquire the lock when
+    /// the state is even, but need to block when odd.
+    state: AtomicU32,
+    /// Incremented to wake up the writers.
+    writer_wake_counter: AtomicU32,
+    data: UnsafeCell<T>,
+    #[cfg(feature = "lockdep")]
+    class: Option<&'static LockClass>,
+    #[cfg(feature = "lock_stats")]
+    stats: OnceLock<Arc<Counters>>,
+}
 
-    impl<T> RwLock<T> {
-        pub const fn new(value: T) -> Self {
-            Self {
-                state: AtomicU32::new(0), // 0: unlocked
-                writer_wake_counter: AtomicU32::new(0),
-                data: UnsafeCell::new(value),
-            }
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+impl<T> RwLock<T> {
+    #[allow(dead_code)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0), // 0: unlocked
+            writer_wake_counter: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+            #[cfg(feature = "lockdep")]
+            class: None,
+            #[cfg(feature = "lock_stats")]
+            stats: OnceLock::new(),
         }
+    }
+
+    /// Creates a lock that shares its lock order with all others of the same `class`.
+    #[cfg(feature = "lockdep")]
+    #[allow(dead_code)]
+    pub const fn with_class(value: T, class: &'static LockClass) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+            class: Some(class),
+            #[cfg(feature = "lock_stats")]
+            stats: OnceLock::new(),
+        }
+    }
 
-        pub fn read(&self) -> ReadGuard<T> {
-            let mut s = self.state.load(Relaxed);
-            loop {
-                if s % 2 == 0 {
-                    assert!(s != u32::MAX - 2, "too many readers");
-                    match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
-                        Ok(_) => return ReadGuard { rwlock: self },
-                        Err(e) => s = e,
+    #[allow(dead_code)]
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        super::lockdep::acquire(self, self.class);
+        #[cfg(feature = "lock_stats")]
+        let mut wait_start = None;
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s % 2 == 0 {
+                assert!(s != u32::MAX - 2, "too many readers");
+                match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                    Ok(_) => {
+                        #[cfg(feature = "deadlock_detection")]
+                        let acquisition = super::deadlock::acquired(self);
+                        #[cfg(feature = "lock_stats")]
+                        self.acquired(wait_start);
+                        return ReadGuard {
+                            rwlock: self,
+                            #[cfg(feature = "deadlock_detection")]
+                            acquisition,
+                            #[cfg(feature = "lock_stats")]
+                            locked_at: Instant::now(),
+                        };
                     }
+                    Err(e) => s = e,
                 }
-                if s % 2 == 1 {
-                    wait(&self.state, s);
-                    s = self.state.load(Relaxed);
+            }
+            if s % 2 == 1 {
+                #[cfg(feature = "deadlock_detection")]
+                super::deadlock::waiting(self);
+                #[cfg(feature = "lock_stats")]
+                {
+                    wait_start.get_or_insert_with(Instant::now);
+                    self.counters().wait();
                 }
+                wait(&self.state, s);
+                s = self.state.load(Relaxed);
             }
         }
+    }
 
-        pub fn write(&self) -> WriteGuard<T> {
-            let mut s = self.state.load(Relaxed);
-            loop {
-                // Try to lock if unlocked
-                if s <= 1 {
-                    match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
-                        Ok(_) => return WriteGuard { rwlock: self },
-                        Err(e) => {
-                            s = e;
-                            continue;
-                        }
+    #[allow(dead_code)]
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        super::lockdep::acquire(self, self.class);
+        #[cfg(feature = "lock_stats")]
+        let mut wait_start = None;
+        let mut s = self.state.load(Relaxed);
+        loop {
+            // Try to lock if unlocked
+            if s <= 1 {
+                match self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => {
+                        #[cfg(feature = "deadlock_detection")]
+                        let acquisition = super::deadlock::acquired(self);
+                        #[cfg(feature = "lock_stats")]
+                        self.acquired(wait_start);
+                        return WriteGuard {
+                            rwlock: self,
+                            #[cfg(feature = "deadlock_detection")]
+                            acquisition,
+                            #[cfg(feature = "lock_stats")]
+                            locked_at: Instant::now(),
+                        };
+                    }
+                    Err(e) => {
+                        s = e;
+                        continue;
                     }
                 }
-                // Block new readers, by making sure the state is odd
-                if s % 2 == 0 {
-                    match self.state.compare_exchange(s, s + 1, Acquire, Relaxed) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            s = e;
-                            continue;
-                        }
+            }
+            // Block new readers, by making sure the state is odd
+            if s % 2 == 0 {
+                match self.state.compare_exchange(s, s + 1, Acquire, Relaxed) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
                     }
                 }
-                // Wait, if it's still locked
-                let w = self.writer_wake_counter.load(Acquire);
-                s = self.state.load(Relaxed);
-                if s >= 2 {
-                    wait(&self.writer_wake_counter, w);
-                    s = self.state.load(Relaxed);
+            }
+            // Wait, if it's still locked
+            let w = self.writer_wake_counter.load(Acquire);
+            s = self.state.load(Relaxed);
+            if s >= 2 {
+                #[cfg(feature = "deadlock_detection")]
+                super::deadlock::waiting(self);
+                #[cfg(feature = "lock_stats")]
+                {
+                    wait_start.get_or_insert_with(Instant::now);
+                    self.counters().wait();
                 }
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Relaxed);
             }
         }
     }
 
-    struct ReadGuard<'a, T> {
-        rwlock: &'a RwLock<T>,
+    #[cfg(feature = "lock_stats")]
+    #[allow(dead_code)]
+    pub fn stats(&self) -> LockStats {
+        self.counters().stats()
     }
 
-    impl<T> Deref for ReadGuard<'_, T> {
-        type Target = T;
-        fn deref(&self) -> &T {
-            unsafe { &*self.rwlock.data.get() }
+    #[cfg(feature = "lock_stats")]
+    fn counters(&self) -> &Counters {
+        self.stats.get_or_init(|| super::lock_stats::register(self))
+    }
+
+    #[cfg(feature = "lock_stats")]
+    fn acquired(&self, wait_start: Option<Instant>) {
+        let counters = self.counters();
+        counters.acquired();
+        if let Some(start) = wait_start {
+            counters.contended(start.elapsed());
         }
     }
+}
 
-    impl<T> Drop for ReadGuard<'_, T> {
-        fn drop(&mut self) {
-            // Decrement the state by 2 to remove one read-lock.
-            if self.rwlock.state.fetch_sub(2, Release) == 3 {
-                // we decrement from 3 to 1, that means
-                // the Rwlock is now unlocked _and_ there is
-                // a waiting writer, which we wake up.
-                self.rwlock.writer_wake_counter.fetch_add(1, Release);
-                wake_one(&self.rwlock.writer_wake_counter);
-            }
+#[cfg(feature = "lockdep")]
+impl<T> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        if self.class.is_none() {
+            super::lockdep::forget(self);
         }
     }
+}
+
+pub struct ReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+    #[cfg(feature = "deadlock_detection")]
+    acquisition: super::deadlock::AcquisitionId,
+    #[cfg(feature = "lock_stats")]
+    locked_at: Instant,
+}
 
-    struct WriteGuard<'a, T> {
-        rwlock: &'a RwLock<T>,
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
     }
+}
 
-    impl<T> Deref for WriteGuard<'_, T> {
-        type Target = T;
-        fn deref(&self) -> &T {
-            unsafe { &*self.rwlock.data.get() }
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "deadlock_detection")]
+        super::deadlock::released(self.rwlock, &self.acquisition);
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.rwlock);
+        #[cfg(feature = "lock_stats")]
+        self.rwlock.counters().released(self.locked_at.elapsed());
+        // Decrement the state by 2 to remove one read-lock.
+        if self.rwlock.state.fetch_sub(2, Release) == 3 {
+            // we decrement from 3 to 1, that means
+            // the Rwlock is now unlocked _and_ there is
+            // a waiting writer, which we wake up.
+            self.rwlock.writer_wake_counter.fetch_add(1, Release);
+            #[cfg(feature = "lock_stats")]
+            self.rwlock.counters().wake();
+            wake_one(&self.rwlock.writer_wake_counter);
         }
     }
+}
 
-    impl<T> DerefMut for WriteGuard<'_, T> {
-        fn deref_mut(&mut self) -> &mut T {
-            unsafe { &mut *self.rwlock.data.get() }
-        }
+pub struct WriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+    #[cfg(feature = "deadlock_detection")]
+    acquisition: super::deadlock::AcquisitionId,
+    #[cfg(feature = "lock_stats")]
+    locked_at: Instant,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
     }
+}
 
-    impl<T> Drop for WriteGuard<'_, T> {
-        fn drop(&mut self) {
-            self.rwlock.state.store(0, Release);
-            self.rwlock.writer_wake_counter.fetch_add(1, Release);
-            wake_one(&self.rwlock.writer_wake_counter);
-            wake_all(&self.rwlock.state);
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "deadlock_detection")]
+        super::deadlock::released(self.rwlock, &self.acquisition);
+        #[cfg(feature = "lockdep")]
+        super::lockdep::release(self.rwlock);
+        #[cfg(feature = "lock_stats")]
+        {
+            let counters = self.rwlock.counters();
+            counters.released(self.locked_at.elapsed());
+            // One for the writers, one for the readers.
+            counters.wake();
+            counters.wake();
         }
+        self.rwlock.state.store(0, Release);
+        self.rwlock.writer_wake_counter.fetch_add(1, Release);
+        wake_one(&self.rwlock.writer_wake_counter);
+        wake_all(&self.rwlock.state);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
 
     #[test]
     fn test1() {
@@ -0,0 +1,109 @@
+use atomic_wait::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::Arc;
+
+/// Waits for a group of threads to finish.
+///
+/// Every thread gets its own clone of the `WaitGroup` and drops it when done.
+/// `wait()` blocks until all clones are gone.
+#[allow(dead_code)]
+pub struct WaitGroup {
+    /// The number of `WaitGroup`s alive.
+    count: Arc<AtomicU32>,
+}
+
+impl WaitGroup {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self {
+            count: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn wait(self) {
+        let count = self.count.clone();
+        // Our own handle shouldn't keep us waiting.
+        drop(self);
+        loop {
+            let n = count.load(Acquire);
+            if n == 0 {
+                return;
+            }
+            wait(&count, n);
+        }
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for WaitGroup {
+    fn clone(&self) -> Self {
+        if self.count.fetch_add(1, Relaxed) > u32::MAX / 2 {
+            std::process::abort();
+        }
+        Self {
+            count: self.count.clone(),
+        }
+    }
+}
+
+impl Drop for WaitGroup {
+    fn drop(&mut self) {
+        if self.count.fetch_sub(1, Release) == 1 {
+            wake_all(&*self.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_group() {
+        let wg = WaitGroup::new();
+        let num_done = Arc::new(AtomicUsize::new(0));
+
+        for i in 0..10 {
+            let wg = wg.clone();
+            let num_done = num_done.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10 * i));
+                num_done.fetch_add(1, Relaxed);
+                drop(wg);
+            });
+        }
+
+        wg.wait();
+        assert_eq!(num_done.load(Relaxed), 10);
+    }
+
+    #[test]
+    fn test_wait_group_multiple_waiters() {
+        let wg = WaitGroup::new();
+        let num_done = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..5 {
+                let wg = wg.clone();
+                let num_done = &num_done;
+                s.spawn(move || {
+                    wg.wait();
+                    assert_eq!(num_done.load(Relaxed), 1);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            num_done.store(1, Relaxed);
+            wg.wait();
+        });
+    }
+}